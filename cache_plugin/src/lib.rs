@@ -13,7 +13,8 @@ use cacher::SNICacher;
 
 
 
-#[no_mangle]
+interfaces::declare_cacher_plugin!(get_cacher);
+
 pub fn get_cacher() -> Box<dyn Cacher> {
     activate_env_logger();
     Box::new(SNICacher::new())
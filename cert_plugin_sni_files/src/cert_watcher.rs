@@ -0,0 +1,137 @@
+use arc_swap::ArcSwap;
+use interfaces::{CertificateExpiry, ClientAuthPolicy, LoadSummary, PluginCertificate};
+#[allow(unused_imports)]
+use logg::{debug, error, info, trace, warn};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::{
+    collections::HashMap,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::channel,
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::sni_resolver::{load_certificates, SniCertificates, DEFAULT_CERT_DIR};
+
+// Seconds to wait for a directory to settle before rescanning, certbot writes several
+// files per renewal. 0 turns the watcher off.
+const DEFAULT_WATCH_DELAY: u64 = 2;
+
+// The certificates and forwards from CERT_DIR, shared between the plugin and the
+// watcher thread. The proxy picks up new certificates when generation changes.
+pub struct CertificateStore {
+    certificates: SniCertificates,
+    forwards: ArcSwap<HashMap<String, String>>,
+    client_auth: ArcSwap<HashMap<String, ClientAuthPolicy>>,
+    generation: AtomicU64,
+    summary: ArcSwap<LoadSummary>,
+}
+
+impl CertificateStore {
+    pub fn load() -> CertificateStore {
+        let store = CertificateStore {
+            certificates: SniCertificates::new(),
+            forwards: ArcSwap::from_pointee(HashMap::new()),
+            client_auth: ArcSwap::from_pointee(HashMap::new()),
+            generation: AtomicU64::new(0),
+            summary: ArcSwap::from_pointee(LoadSummary::default()),
+        };
+        store.refresh();
+        store
+    }
+
+    // Scans the directory again and publishes the result.
+    pub fn refresh(&self) {
+        let (by_name, forwards, client_auth, summary) = load_certificates();
+        self.certificates.store(by_name);
+        self.forwards.store(Arc::new(forwards));
+        self.client_auth.store(Arc::new(client_auth));
+        self.summary.store(Arc::new(summary));
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn certificates(&self) -> Vec<PluginCertificate> {
+        self.certificates.certificates()
+    }
+
+    pub fn forwards(&self) -> Arc<HashMap<String, String>> {
+        self.forwards.load_full()
+    }
+
+    pub fn client_auth(&self) -> Arc<HashMap<String, ClientAuthPolicy>> {
+        self.client_auth.load_full()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn summary(&self) -> LoadSummary {
+        LoadSummary::clone(&self.summary.load())
+    }
+
+    pub fn expiry(&self) -> Vec<CertificateExpiry> {
+        self.certificates.expiry()
+    }
+}
+
+// Starts a thread that watches CERT_DIR (and CERT_FORWARDS_FILE) with inotify and
+// refreshes the store when anything in it changes, so certbot renewals, new and
+// removed certificates are served without a restart.
+pub fn watch_for_changes(store: Arc<CertificateStore>) {
+    let delay: u64 = dotenv::var("CERT_WATCH_DELAY")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(DEFAULT_WATCH_DELAY);
+    if delay == 0 {
+        info!(target: "0","CERT_WATCH_DELAY is 0, not watching the certificate directory");
+        return;
+    }
+    let cert_dir = dotenv::var("CERT_DIR").unwrap_or_else(|_| String::from(DEFAULT_CERT_DIR));
+
+    let (tx, rx) = channel();
+    let mut watcher = match notify::watcher(tx, Duration::from_secs(delay)) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!(target: "0","Unable to watch for certificate changes: {:?}",e);
+            return;
+        }
+    };
+    if let Err(e) = watcher.watch(&cert_dir, RecursiveMode::Recursive) {
+        error!(target: "0","Unable to watch CERT_DIR {}: {:?}",cert_dir,e);
+        return;
+    }
+    if let Ok(forwards_file) = dotenv::var("CERT_FORWARDS_FILE") {
+        if let Err(e) = watcher.watch(&forwards_file, RecursiveMode::NonRecursive) {
+            warn!(target: "0","Unable to watch CERT_FORWARDS_FILE {}: {:?}",forwards_file,e);
+        }
+    }
+
+    info!(target: "0","Watching {} for certificate changes",cert_dir);
+    thread::spawn(move || {
+        //Moved in here, the watch stops when the watcher is dropped.
+        let _watcher = watcher;
+        for event in rx.iter() {
+            match event {
+                DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => continue,
+                DebouncedEvent::Error(e, path) => {
+                    warn!(target: "0","Certificate watcher error {:?}: {:?}",path,e);
+                    continue;
+                }
+                event => debug!(target: "0","Certificate directory changed: {:?}",event),
+            }
+            //Several files change per renewal, take them all in one rescan.
+            while rx.recv_timeout(Duration::from_millis(500)).is_ok() {}
+            info!(target: "0","Certificate directory changed, rescanning");
+            //A broken certificate must not take the watcher down with it.
+            if catch_unwind(AssertUnwindSafe(|| store.refresh())).is_err() {
+                error!(target: "0","Rescanning certificates panicked, keeping the current ones");
+            }
+        }
+        warn!(target: "0","Certificate watcher stopped");
+    });
+}
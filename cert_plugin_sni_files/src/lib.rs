@@ -1,65 +1,82 @@
 //extern crate log as logg;
-mod sni_resolver;
+mod cert_watcher;
 mod env_logger;
-use interfaces::CertificateHandler;
+mod sni_resolver;
+use interfaces::{
+    CertificateExpiry, CertificateHandler, ClientAuthPolicy, LoadSummary, PluginCertificate,
+};
 
+use cert_watcher::{watch_for_changes, CertificateStore};
 use env_logger::activate_env_logger;
 #[allow(unused_imports)]
 use logg::{debug, error, info, trace, warn};
+use sni_resolver::save_certificate;
 use std::{collections::HashMap, sync::Arc};
-use sni_resolver::load_resolver;
-use rustls::Certificate;
 
-#[no_mangle]
-// pub fn get_certificate_handler() -> Box<dyn CertificateHandler> {
-//     activate_env_logger();
-//     Box::new(CH::new())
-// }
+interfaces::declare_certificate_handler_plugin!(get_certificate_handler);
+
+pub fn get_certificate_handler() -> Result<Box<dyn CertificateHandler>, String> {
+    activate_env_logger();
+    Ok(Box::new(CH::new()))
+}
 #[allow(dead_code)]
 pub struct CH {
     id: String,
-    import_certificates: Vec<Certificate>,
+    store: Arc<CertificateStore>,
 }
 
 impl CH {
-    // fn new() -> CH {
-    //     let id = format!("{:08x}", rand::random::<u32>());
-    //     println!("[{}] Created instance!", id);
-    //     let import_certificates: Vec<Certificate>=Vec::new();// = get_all_certificates();
-    //     CH {
-    //         id,
-    //         import_certificates,
-    //     }
-    // }
+    fn new() -> CH {
+        let id = format!("{:08x}", rand::random::<u32>());
+        debug!(target: "0","[{}] Created certificate plugin instance",id);
+        let store = Arc::new(CertificateStore::load());
+        watch_for_changes(store.clone());
+        CH { id, store }
+    }
 }
 
 impl CertificateHandler for CH {
     fn get_forwards(&self) -> Box<Arc<HashMap<String, String>>> {
-        info!(target: "0","Load forwards from database");
-        let import_certificates = &self.import_certificates;
-        let mut forwards: HashMap<String, String> = HashMap::new();
-        // for cert in import_certificates
-        //     .iter()
-        //     .filter(|c| !c.forward.contains("127"))
-        // {
-        //     if cert.domain_names.is_some() {
-        //         for dn in cert.domain_names.as_ref().unwrap() {
-        //             if None == forwards.insert(String::from(&dn.dn), String::from(&cert.forward)) {
-        //                 //Hosts println!("127.0.0.1   {0}  # {0}  => {1}",dn.dn,cert.forward);
-        //             }
-        //         }
-        //     }
-        // }
-        // info!(target: "0","Forwards done");
-        Box::new(Arc::new(forwards.clone()))
+        info!(target: "0","Load forwards from certificate directory");
+        Box::new(self.store.forwards())
+    }
+
+    fn certificates(&self) -> Vec<PluginCertificate> {
+        self.store.certificates()
+    }
+
+    fn reload(&mut self) -> Result<(), String> {
+        info!(target: "0","Rescanning certificate directory");
+        self.store.refresh();
+        Ok(())
+    }
+
+    fn generation(&self) -> u64 {
+        self.store.generation()
+    }
+
+    fn load_summary(&self) -> LoadSummary {
+        self.store.summary()
+    }
+
+    fn certificate_expiry(&self) -> Vec<CertificateExpiry> {
+        self.store.expiry()
     }
 
-    fn get_sni_resolver(&self) -> Box<Arc<dyn rustls::ResolvesServerCert>> {
-        info!(target: "0","Resolver");
-        
-        let (resolver,forwards) = load_resolver();
+    fn store_certificate(
+        &mut self,
+        names: &[String],
+        forward: &str,
+        fullchain: &str,
+        privkey: &str,
+    ) -> Result<(), String> {
+        save_certificate(names, forward, fullchain, privkey)?;
+        //Served right away, the watcher would only get to it after CERT_WATCH_DELAY.
+        self.store.refresh();
+        Ok(())
+    }
 
-        info!(target: "0","Resolver done");
-        Box::new(Arc::new(resolver).clone())
+    fn client_auth(&self) -> HashMap<String, ClientAuthPolicy> {
+        HashMap::clone(&self.store.client_auth())
     }
 }
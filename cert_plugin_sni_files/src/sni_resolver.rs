@@ -1,182 +1,303 @@
-use regex::Regex;
-use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
-use rustls::sign::{RSASigningKey, SigningKey};
-use rustls::{sign, ClientHello, ResolvesServerCert, TLSError};
-use std::collections;
-use std::fs::{self, File};
-use std::io::BufReader;
-use std::sync::Arc;
+use arc_swap::ArcSwap;
+use interfaces::{
+    CertificateError, CertificateExpiry, ClientAuthPolicy, LoadSummary, LoadedCertificate,
+    PluginCertificate, SniNames,
+};
+#[allow(unused_imports)]
 use logg::{debug, error, info, trace, warn};
-use std::{collections::HashMap};
-
-
-pub fn load_resolver() -> (MyResolvesServerCertUsingSNI, HashMap<String,String>) {
-    let mut forwards:HashMap<String,String> = HashMap::new();
-    let mut resolver = MyResolvesServerCertUsingSNI::new();
-    add_certificate_to_resolver(
-        "partof-path.domain.name.com",
-        "domain.name.com",
-        &mut resolver,
-        &mut forwards,
-    );
-    add_certificate_to_resolver(
-        "partof-path.other.domain.name.com",
-        "other-domain.name.com",
-        &mut resolver,
-        &mut forwards,
-    );
-    //add_certificate_to_resolver("_default_", "localhost", &mut resolver);
-    (resolver, forwards)
-}
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use x509_parser::extensions::GeneralName;
 
+// certbot keeps every certificate in its own directory under live/, we do the same.
+pub const DEFAULT_CERT_DIR: &str = "/etc/letsencrypt/live";
+// Put next to fullchain.pem/privkey.pem, holds the forward for all names in the certificate.
+const FORWARD_SIDECAR: &str = "forward.txt";
+// Also next to it, the CAs client certificates for the names must be issued by and
+// none, optional or required (the default when there is a client_ca.pem).
+const CLIENT_CA_SIDECAR: &str = "client_ca.pem";
+const CLIENT_AUTH_SIDECAR: &str = "client_auth.txt";
 
-pub struct MyResolvesServerCertUsingSNI {
-    by_name: collections::HashMap<String, sign::CertifiedKey>,
-}
+//More than one per name when there is e.g. both an RSA and an ECDSA certificate.
+pub type CertificatesByName = SniNames<Vec<LoadedCertificate>>;
 
+// Scans CERT_DIR for directories with a fullchain.pem and privkey.pem and loads them all.
+// Forwards come from a forward.txt in the certificate directory, or from the file in
+// CERT_FORWARDS_FILE with one "hostname forward" per line, which wins when both exist.
+pub fn load_certificates() -> (
+    CertificatesByName,
+    HashMap<String, String>,
+    HashMap<String, ClientAuthPolicy>,
+    LoadSummary,
+) {
+    let cert_dir = dotenv::var("CERT_DIR").unwrap_or_else(|_| String::from(DEFAULT_CERT_DIR));
+    let mut forwards: HashMap<String, String> = HashMap::new();
+    let mut client_auth: HashMap<String, ClientAuthPolicy> = HashMap::new();
+    let mut by_name: CertificatesByName = SniNames::new();
 
-impl MyResolvesServerCertUsingSNI {
-    pub fn new() -> MyResolvesServerCertUsingSNI {
-        MyResolvesServerCertUsingSNI {
-            by_name: collections::HashMap::new(),
+    let mut cert_dirs: Vec<PathBuf> = match fs::read_dir(&cert_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.join("fullchain.pem").is_file() && path.join("privkey.pem").is_file()
+            })
+            .collect(),
+        Err(e) => {
+            error!(target: "0","Unable to read CERT_DIR {}: {}",cert_dir,e);
+            Vec::new()
+        }
+    };
+    //Sorted so the __default__ certificate is the same every time.
+    cert_dirs.sort();
+    //DEFAULT_CERT_NAME picks the directory served to clients without a known SNI name.
+    if let Ok(default_name) = dotenv::var("DEFAULT_CERT_NAME") {
+        if let Some(i) = cert_dirs
+            .iter()
+            .position(|dir| dir.ends_with(&default_name))
+        {
+            let dir = cert_dirs.remove(i);
+            cert_dirs.insert(0, dir);
         }
     }
-    pub fn add(&mut self, name: &str, ck: sign::CertifiedKey) -> Result<(), TLSError> {
-        if self.by_name.is_empty() {
-            self.by_name.insert(String::from("__default__"), ck.clone());
+
+    let mut summary = LoadSummary::default();
+    for dir in &cert_dirs {
+        match add_certificate(dir, &mut by_name, &mut forwards, &mut client_auth) {
+            Ok(()) => summary.loaded += 1,
+            Err(e) => {
+                error!(target: "0","Skipping certificate {}: {}",dir.display(),e);
+                summary.add_error(&e);
+            }
         }
+    }
 
-        self.by_name.insert(name.into(), ck);
-        Ok(())
+    if let Ok(forwards_file) = dotenv::var("CERT_FORWARDS_FILE") {
+        match fs::read_to_string(&forwards_file) {
+            Ok(mapping) => {
+                for (hostname, forward) in parse_forwards(&mapping) {
+                    forwards.insert(hostname, forward);
+                }
+            }
+            Err(e) => {
+                error!(target: "0","Unable to read CERT_FORWARDS_FILE {}: {}",forwards_file,e)
+            }
+        }
     }
+    info!(target: "0","Loaded {} certificates and {} forwards from {}, {} skipped, {} expired",summary.loaded,forwards.len(),cert_dir,summary.skipped,summary.expired);
+    (by_name, forwards, client_auth, summary)
+}
+
+// "hostname forward" per line, # starts a comment.
+fn parse_forwards(mapping: &str) -> Vec<(String, String)> {
+    mapping
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(hostname), Some(forward)) => {
+                    Some((hostname.to_lowercase(), String::from(forward)))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// by_name is only ever replaced as a whole, so the proxy never sees a half loaded directory.
+pub struct SniCertificates {
+    by_name: ArcSwap<CertificatesByName>,
 }
 
-impl ResolvesServerCert for MyResolvesServerCertUsingSNI {
-    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertifiedKey> {
-        if client_hello.server_name().is_none() {
-            trace!("cannot look up certificate: no SNI from session");
-            return None;
+impl SniCertificates {
+    pub fn new() -> SniCertificates {
+        SniCertificates {
+            by_name: ArcSwap::from_pointee(SniNames::new()),
+        }
+    }
+
+    pub fn store(&self, by_name: CertificatesByName) {
+        self.by_name.store(Arc::new(by_name));
+    }
+
+    pub fn expiry(&self) -> Vec<CertificateExpiry> {
+        let by_name = self.by_name.load();
+        let mut expiry = Vec::new();
+        for (name, certificates) in by_name.iter().filter(|(name, _)| name != "__default__") {
+            for loaded in certificates {
+                expiry.push(CertificateExpiry::new(&name, loaded));
+            }
         }
-        let name: &str = client_hello.server_name().unwrap().into();
-        trace!(
-            "trying to resolve name: {:?} for signature scheme: {:?}",
-            name,
-            client_hello.sigschemes()
-        );
-
-        if let Some(dnsname) = client_hello.server_name() {
-            if self.by_name.contains_key(dnsname.into()) {
-                trace!("1. lookup successfull for server name '{:?}'", dnsname);
-                self.by_name.get(dnsname.into()).cloned()
+        expiry
+    }
+
+    // Every certificate once, with all the names it is served for.
+    pub fn certificates(&self) -> Vec<PluginCertificate> {
+        let by_name = self.by_name.load();
+        let mut certificates: Vec<PluginCertificate> = Vec::new();
+        //Keyed on the PEMs, a certificate is in by_name once per name.
+        let mut index: HashMap<(Arc<str>, Arc<str>), usize> = HashMap::new();
+        for (name, loaded) in by_name
+            .iter()
+            .flat_map(|(name, all)| all.iter().map(move |loaded| (name.clone(), loaded)))
+        {
+            let key = (loaded.fullchain.clone(), loaded.privkey.clone());
+            let i = *index.entry(key).or_insert_with(|| {
+                certificates.push(PluginCertificate {
+                    names: Vec::new(),
+                    fullchain: String::from(&*loaded.fullchain),
+                    privkey: String::from(&*loaded.privkey),
+                    default: false,
+                });
+                certificates.len() - 1
+            });
+            if name == "__default__" {
+                certificates[i].default = true;
             } else {
-                trace!(
-                    "2. could not look up a certificate for server name '{:?}' trying __default__!",
-                    dnsname
-                );
-                self.by_name.get("__default__").cloned()
+                certificates[i].names.push(name);
             }
-        } else {
-            trace!(
-                "3. could not look up a certificate for server name '{:?}' trying __default__!",
-                client_hello.server_name()
-            );
-            self.by_name.get("__default__").cloned()
-            // None
         }
+        certificates
     }
 }
 
-
-pub fn add_certificate_to_resolver<'a>(
-    name: &str,
-    hostname: &str,
-    resolver: &mut MyResolvesServerCertUsingSNI,
-    forwards: &mut HashMap<String,String>,
-) {
-    let cert_file = File::open(format!("../certificates/{}/fullchain.pem", name));
-    if cert_file.is_err() {
-        println!("error loading file: ../certificates/{}/fullchain.pem", name);
-        return ();
+fn add(by_name: &mut CertificatesByName, name: &str, loaded: LoadedCertificate) {
+    if by_name.is_empty() {
+        by_name.push("__default__", loaded.clone());
     }
-    let key_file = File::open(format!("../certificates/{}/privkey.pem", name));
-    if key_file.is_err() {
-        println!("error loading file: ../certificates/{}/privkey.pem", name);
-        return ();
+
+    if !by_name.push(name, loaded) {
+        warn!(target: "0","Ignoring invalid certificate name {}",name);
     }
+}
 
-    let cert = &mut BufReader::new(cert_file.unwrap());
-    let cert_chain = certs(cert).unwrap();
+// Loads one certbot style directory, the certificate is served for every DNS name in its
+// SANs (or the directory name if it has none).
+pub fn add_certificate(
+    dir: &Path,
+    by_name: &mut CertificatesByName,
+    forwards: &mut HashMap<String, String>,
+    client_auth: &mut HashMap<String, ClientAuthPolicy>,
+) -> Result<(), CertificateError> {
+    let cert_path = dir.join("fullchain.pem");
+    let key_path = dir.join("privkey.pem");
 
-    let (_, x509) = x509_parser::parse_x509_der(cert_chain[0].as_ref()).unwrap();
-    let (_, x509) = x509.tbs_certificate.subject_alternative_name().unwrap();
-    let ref mut names_vec: Vec<String> = Vec::new();
-    let re = Regex::new(r#"\("(.*?)"\)"#).unwrap();
-    let text = String::from(format!("{:?}", x509.general_names));
-    println!("NamesHost: {}", hostname);
-    for cap in re.captures_iter(text.as_str()) {
-        names_vec.push((&cap[1]).to_string());
+    let fullchain = fs::read_to_string(&cert_path)
+        .map_err(|e| format!("error loading file {}: {}", cert_path.display(), e))?;
+    let privkey = fs::read_to_string(&key_path)
+        .map_err(|e| format!("error loading file {}: {}", key_path.display(), e))?;
+    let loaded = LoadedCertificate::from_pem(&fullchain, &privkey)?;
 
-        println!("regex {}", &cap[1]);
+    let mut names_vec = certificate_dns_names(&loaded.ck.cert[0])?;
+    if names_vec.is_empty() {
+        if let Some(name) = dir.file_name() {
+            names_vec.push(name.to_string_lossy().to_lowercase());
+        }
     }
 
-    let mut keys = rsa_private_keys(&mut BufReader::new(
-        File::open(format!("../certificates/{}/privkey.pem", name)).unwrap(),
-    ))
-    .unwrap();
-
-    if keys.len() == 0 {
-        let key = &mut BufReader::new(
-            File::open(format!("../certificates/{}/privkey.pem", name)).unwrap(),
-        );
-        keys = pkcs8_private_keys(key).unwrap();
-    };
-
-    let signing_key = RSASigningKey::new(&keys.remove(0)).unwrap();
+    let forward = fs::read_to_string(dir.join(FORWARD_SIDECAR))
+        .ok()
+        .and_then(|forward| {
+            forward
+                .lines()
+                .map(str::trim)
+                .find(|l| !l.is_empty())
+                .map(String::from)
+        });
 
-    let signing_key_boxed: Arc<Box<dyn SigningKey>> = Arc::new(Box::new(signing_key));
+    let policy = client_auth_policy(dir);
 
-    resolver
-        .add(
-            hostname,
-            rustls::sign::CertifiedKey::new(cert_chain.clone(), signing_key_boxed.clone()),
-        )
-        .expect(&format!("Invalid certificate for {}:{}", hostname, name));
     for name in names_vec {
-        resolver
-            .add(
-                name,
-                rustls::sign::CertifiedKey::new(cert_chain.clone(), signing_key_boxed.clone()),
-            )
-            .expect(&format!("Invalid certificate for {}:{}", hostname, name));
+        debug!(target: "0","{} mapping {} => {:?}",dir.display(),name,forward);
+        if let Some(forward) = forward.as_ref() {
+            forwards.insert(name.clone(), forward.clone());
+        }
+        if let Some(policy) = policy.as_ref() {
+            client_auth.insert(name.clone(), policy.clone());
+        }
+        add(by_name, &name, loaded.clone());
     }
+    Ok(())
 }
 
+// A policy that can't be read lets no client in rather than none.
+fn client_auth_policy(dir: &Path) -> Option<ClientAuthPolicy> {
+    let mode = fs::read_to_string(dir.join(CLIENT_AUTH_SIDECAR)).ok();
+    let ca_pem = match fs::read_to_string(dir.join(CLIENT_CA_SIDECAR)) {
+        Ok(ca_pem) => ca_pem,
+        Err(_) if mode.is_none() => return None,
+        Err(_) => String::new(),
+    };
+    let mode = mode.unwrap_or_else(|| String::from("required"));
+    match ClientAuthPolicy::from_pem(&mode, &ca_pem) {
+        Ok(policy) => Some(policy),
+        Err(e) => {
+            error!(target: "0","Client auth for {} denies everyone: {}",dir.display(),e);
+            Some(ClientAuthPolicy::deny_all())
+        }
+    }
+}
 
-pub fn load_certs(filename: &str) -> Vec<rustls::Certificate> {
-    let certfile = fs::File::open(filename).expect("cannot open certificate file");
-    let mut reader = BufReader::new(certfile);
-    rustls::internal::pemfile::certs(&mut reader).unwrap()
+// Writes a certificate to CERT_DIR/<first name>/ the way certbot would, replacing what
+// was there. The key is only readable by us, every file is written to a temporary name
+// first so a rescan never sees half a certificate.
+pub fn save_certificate(
+    names: &[String],
+    forward: &str,
+    fullchain: &str,
+    privkey: &str,
+) -> Result<(), String> {
+    let name = names
+        .first()
+        .ok_or_else(|| String::from("No name for the certificate"))?;
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(format!("Unable to use {:?} as a directory name", name));
+    }
+    let cert_dir = dotenv::var("CERT_DIR").unwrap_or_else(|_| String::from(DEFAULT_CERT_DIR));
+    let dir = Path::new(&cert_dir).join(name);
+    fs::create_dir_all(&dir).map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+
+    //Each file is replaced on its own, a rescan in between sees a key that doesn't match
+    //the certificate and skips the directory until the next one.
+    write_file(&dir.join("privkey.pem"), privkey, 0o600)?;
+    write_file(&dir.join("fullchain.pem"), fullchain, 0o644)?;
+    write_file(&dir.join(FORWARD_SIDECAR), &format!("{}\n", forward), 0o644)?;
+    info!(target: "0","Saved certificate for {} in {}",names.join(", "),dir.display());
+    Ok(())
 }
 
-pub fn load_private_key(filename: &str) -> rustls::PrivateKey {
-    let rsa_keys = {
-        let keyfile = fs::File::open(filename).expect("cannot open private key file");
-        let mut reader = BufReader::new(keyfile);
-        rustls::internal::pemfile::rsa_private_keys(&mut reader)
-            .expect("file contains invalid rsa private key")
-    };
+fn write_file(path: &Path, contents: &str, mode: u32) -> Result<(), String> {
+    let tmp = path.with_extension("tmp");
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
 
-    let pkcs8_keys = {
-        let keyfile = fs::File::open(filename).expect("cannot open private key file");
-        let mut reader = BufReader::new(keyfile);
-        rustls::internal::pemfile::pkcs8_private_keys(&mut reader)
-            .expect("file contains invalid pkcs8 private key (encrypted keys not supported)")
+// The DNS names in the subjectAltName extension, lowercased like SNI names.
+fn certificate_dns_names(cert: &rustls::Certificate) -> Result<Vec<String>, String> {
+    let (_, x509) = x509_parser::parse_x509_der(cert.as_ref())
+        .map_err(|e| format!("unable to parse certificate: {:?}", e))?;
+    let names = match x509.tbs_certificate.subject_alternative_name() {
+        Some((_, san)) => san
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns_name) => Some(dns_name.to_lowercase()),
+                _ => None,
+            })
+            .collect(),
+        None => Vec::new(),
     };
-
-    if !pkcs8_keys.is_empty() {
-        pkcs8_keys[0].clone()
-    } else {
-        assert!(!rsa_keys.is_empty());
-        rsa_keys[0].clone()
-    }
+    Ok(names)
 }
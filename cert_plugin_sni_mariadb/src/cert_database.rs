@@ -1,25 +1,34 @@
+use arc_swap::ArcSwap;
 use collections::HashMap;
+use interfaces::{
+    unix_now, CertificateError, CertificateExpiry, ClientAuthPolicy, LoadSummary,
+    LoadedCertificate, PluginCertificate, SniNames,
+};
 #[allow(unused_imports)]
 use logg::{debug, error, info, trace, warn};
-use mysql::{prelude::Queryable, PooledConn};
-use rustls::{
-    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
-    sign, ResolvesServerCert,
-};
-use sign::{RSASigningKey, SigningKey};
-use std::{
-    collections,
-    io::{Cursor, Error},
-    sync::Arc,
-};
-pub type Result<T> = std::result::Result<T, Error>;
+use mysql::{params, prelude::Queryable, PooledConn, TxOpts};
+use std::{collections, sync::Arc};
+
+pub fn connect() -> std::result::Result<PooledConn, String> {
+    let maria_uri = dotenv::var("MARIA_URI").map_err(|e| format!("MARIA_URI: {}", e))?;
+    let pool: mysql::Pool =
+        mysql::Pool::new(maria_uri).map_err(|e| format!("Error connecting: {:?}", e))?;
+    pool.get_conn()
+        .map_err(|e| format!("Error connecting: {:?}", e))
+}
 
-pub fn get_all_certificates() -> Vec<Certificate> {
-    let pool: mysql::Pool = mysql::Pool::new(dotenv::var("MARIA_URI").unwrap()).unwrap();
-    let mut conn = pool.get_conn().unwrap();
+// Rows whose names can't be read are logged, counted in summary and left out.
+pub fn get_all_certificates(
+    summary: &mut LoadSummary,
+) -> std::result::Result<Vec<Certificate>, String> {
+    let select_crt_table =
+        dotenv::var("SELECT_CRT_TABLE").map_err(|e| format!("SELECT_CRT_TABLE: {}", e))?;
+    let select_dn = dotenv::var("SELECT_DN_FROM_CERT_ID")
+        .map_err(|e| format!("SELECT_DN_FROM_CERT_ID: {}", e))?;
+    let mut conn = connect()?;
 
     let mut selected_certificates = match conn.query_map(
-        dotenv::var("SELECT_CRT_TABLE").unwrap(),
+        select_crt_table,
         |(id, fullchain, privkey, forward, active)| Certificate {
             id,
             fullchain,
@@ -27,18 +36,106 @@ pub fn get_all_certificates() -> Vec<Certificate> {
             forward,
             active,
             domain_names: None,
+            client_auth: None,
         },
     ) {
         Ok(a) => a,
         Err(e) => {
-            panic!("Error Selecting: {:?}", e);
+            return Err(format!("Error Selecting: {:?}", e));
         }
     };
 
-    for c in &mut selected_certificates {
-        c.fill_dn(&mut conn);
+    selected_certificates.retain_mut(|c| match c.fill_dn(&mut conn, &select_dn) {
+        Ok(()) => true,
+        Err(e) => {
+            let e = CertificateError::from(e);
+            warn!(target: "0","Skipping certificate id {}: {}",c.id,e);
+            summary.add_error(&e);
+            false
+        }
+    });
+    fill_client_auth(&mut conn, &mut selected_certificates)?;
+    Ok(selected_certificates)
+}
+
+// SELECT_CLIENT_AUTH gives cert_id, client_auth (none, optional or required) and
+// client_ca (PEM) for the certificates whose names ask for client certificates. Without
+// it no host does.
+fn fill_client_auth(
+    conn: &mut PooledConn,
+    certificates: &mut [Certificate],
+) -> std::result::Result<(), String> {
+    let select_client_auth = match dotenv::var("SELECT_CLIENT_AUTH") {
+        Ok(select_client_auth) => select_client_auth,
+        Err(_) => return Ok(()),
+    };
+    let rows: Vec<(i32, String, String)> = conn
+        .query(select_client_auth)
+        .map_err(|e| format!("Error selecting client auth: {:?}", e))?;
+    for (cert_id, mode, client_ca) in rows {
+        let c = match certificates.iter_mut().find(|c| c.id == cert_id) {
+            Some(c) => c,
+            None => continue,
+        };
+        //A policy that can't be read lets no client in rather than none.
+        c.client_auth = Some(
+            ClientAuthPolicy::from_pem(&mode, &client_ca).unwrap_or_else(|e| {
+                error!(target: "0","Client auth for certificate id {} denies everyone: {}",cert_id,e);
+                ClientAuthPolicy::deny_all()
+            }),
+        );
+    }
+    Ok(())
+}
+
+// Used unless INSERT_CRT/INSERT_DN are set, like the SELECTs they follow the tables in
+// databases_used.sql.
+const DEFAULT_INSERT_CRT: &str = "INSERT INTO certificate (fullchain, privkey, forward, active) VALUES (:fullchain, :privkey, :forward, 'Y')";
+const DEFAULT_INSERT_DN: &str =
+    "INSERT INTO cert_domainname (cert_id, dn, ca_primary) VALUES (:cert_id, :dn, :ca_primary)";
+
+// Adds an active certificate with its names in one transaction, the first name is the
+// primary one. Older certificates for the names are left, the resolver prefers the
+// newest.
+pub fn insert_certificate(
+    names: &[String],
+    forward: &str,
+    fullchain: &str,
+    privkey: &str,
+) -> std::result::Result<(), String> {
+    let insert_crt = dotenv::var("INSERT_CRT").unwrap_or_else(|_| String::from(DEFAULT_INSERT_CRT));
+    let insert_dn = dotenv::var("INSERT_DN").unwrap_or_else(|_| String::from(DEFAULT_INSERT_DN));
+    let mut conn = connect()?;
+    let mut tx = conn
+        .start_transaction(TxOpts::default())
+        .map_err(|e| format!("Error starting transaction: {:?}", e))?;
+    tx.exec_drop(
+        insert_crt,
+        params! {
+            "fullchain" => fullchain,
+            "privkey" => privkey,
+            "forward" => forward,
+        },
+    )
+    .map_err(|e| format!("INSERT_CRT: {:?}", e))?;
+    let cert_id = tx
+        .last_insert_id()
+        .ok_or_else(|| String::from("INSERT_CRT did not give us an id"))?;
+    for (i, name) in names.iter().enumerate() {
+        tx.exec_drop(
+            &insert_dn,
+            params! {
+                "cert_id" => cert_id,
+                "dn" => name,
+                "ca_primary" => if i == 0 { "Y" } else { "N" },
+            },
+        )
+        .map_err(|e| format!("INSERT_DN: {:?}", e))?;
     }
-    selected_certificates
+    tx.commit()
+        .map_err(|e| format!("Error committing certificate: {:?}", e))?;
+    info!(target: "0","Inserted certificate id {} for {}",cert_id,names.join(", "));
+    Ok(())
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -49,13 +146,19 @@ pub struct Certificate {
     pub forward: String,
     pub active: String,
     pub domain_names: Option<Vec<CertDomainName>>,
+    pub client_auth: Option<ClientAuthPolicy>,
 }
 
 impl Certificate {
-    pub fn fill_dn(&mut self, conn: &mut PooledConn) {
+    pub fn fill_dn(
+        &mut self,
+        conn: &mut PooledConn,
+        select_dn: &str,
+    ) -> std::result::Result<(), String> {
         let mut vars = HashMap::new();
         vars.insert("cert_id".to_string(), self.id.to_string());
-        let q = strfmt::strfmt(&dotenv::var("SELECT_DN_FROM_CERT_ID").unwrap(), &vars).unwrap();
+        let q = strfmt::strfmt(select_dn, &vars)
+            .map_err(|e| format!("SELECT_DN_FROM_CERT_ID: {:?}", e))?;
 
         self.domain_names = Some(
             conn.query_map(q, |(id, cert_id, dn, ca_primary)| {
@@ -68,30 +171,9 @@ impl Certificate {
                 trace!("{} => {} => {}", self.id, &dn.dn, self.forward);
                 dn
             })
-            .unwrap(),
+            .map_err(|e| format!("Error selecting domain names for {}: {:?}", self.id, e))?,
         );
-    }
-
-    pub fn verify_and_get_ck(&self) -> Result<sign::CertifiedKey> {
-        let mut cert_bf = Cursor::new(&self.fullchain);
-        let mut key_bf = Cursor::new(&self.privkey);
-
-        let cert_chain = certs(&mut cert_bf).expect("Trying to read cert as buf");
-        let mut keys = rsa_private_keys(&mut key_bf).expect("Trying to read key as buf");
-        if keys.len() == 0 {
-            let mut key_bf = Cursor::new(&self.privkey);
-            keys = pkcs8_private_keys(&mut key_bf)
-                .expect("Trying to get a key longer than 0 from pkcs8!");
-        };
-
-        let signing_key = RSASigningKey::new(&keys.remove(0)).expect("Trying to RSA Sign Key");
-
-        let signing_key_boxed: Arc<Box<dyn SigningKey>> = Arc::new(Box::new(signing_key));
-
-        Ok(rustls::sign::CertifiedKey::new(
-            cert_chain.clone(),
-            signing_key_boxed.clone(),
-        ))
+        Ok(())
     }
 }
 
@@ -103,67 +185,126 @@ pub struct CertDomainName {
     pub ca_primary: String,
 }
 
+// Everything the proxy gets from us, swapped as a whole so it never sees half an update.
+#[derive(Default)]
+struct CertificatesState {
+    default_cert_id: Option<i32>,
+    //A name can have more than one certificate, e.g. an RSA and an ECDSA one.
+    name_to_cert_id_lookup: SniNames<Vec<i32>>,
+    cert_id_to_cert_lookup: collections::HashMap<i32, LoadedCertificate>,
+    //The PEMs each key was made from, so unchanged rows are not parsed again.
+    cert_id_to_pem_lookup: collections::HashMap<i32, (String, String)>,
+}
+
 #[allow(dead_code)]
-pub struct MariaSNIResolver {
-    default_cert: Option<sign::CertifiedKey>,
-    name_to_cert_id_lookup: collections::HashMap<String, i32>,
-    cert_id_to_cert_lookup: collections::HashMap<i32, sign::CertifiedKey>,
+pub struct MariaCertificates {
+    state: ArcSwap<CertificatesState>,
 }
 
 #[allow(dead_code)]
-impl MariaSNIResolver {
-    pub fn new() -> MariaSNIResolver {
-        MariaSNIResolver {
-            default_cert: None,
-            name_to_cert_id_lookup: collections::HashMap::new(),
-            cert_id_to_cert_lookup: collections::HashMap::new(),
+impl MariaCertificates {
+    pub fn new() -> MariaCertificates {
+        MariaCertificates {
+            state: ArcSwap::from_pointee(CertificatesState::default()),
         }
     }
 
-    pub fn populate(&mut self, certs: &Vec<Certificate>) {
+    // Replaces what we serve with certs. Keys for rows with the same fullchain and
+    // privkey as last time are reused, only new and changed rows are parsed. Rows that
+    // don't parse or have expired are logged, counted in summary and left out.
+    pub fn populate(&self, certs: &Vec<Certificate>, summary: &mut LoadSummary) {
+        let old = self.state.load();
+        let mut new = CertificatesState::default();
+        let (mut reused, mut parsed) = (0, 0);
         for c in certs {
-            let ck = c.verify_and_get_ck().expect("Trying to get a CK").clone();
+            let pem = (c.fullchain.clone(), c.privkey.clone());
+            let ck = match (
+                old.cert_id_to_pem_lookup.get(&c.id),
+                old.cert_id_to_cert_lookup.get(&c.id),
+            ) {
+                //Parsed before, but it may have expired since.
+                (Some(old_pem), Some(loaded)) if *old_pem == pem => {
+                    reused += 1;
+                    if loaded.not_after <= unix_now() {
+                        Err(CertificateError::Expired {
+                            not_after: loaded.not_after,
+                        })
+                    } else {
+                        Ok(loaded.clone())
+                    }
+                }
+                _ => {
+                    parsed += 1;
+                    LoadedCertificate::from_pem(&c.fullchain, &c.privkey)
+                }
+            };
+            let loaded = match ck {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    warn!(target: "0","Skipping certificate id {}: {}",c.id,e);
+                    summary.add_error(&e);
+                    continue;
+                }
+            };
+            summary.loaded += 1;
 
             if dotenv::var("DEFAULT_CRT_ID").is_ok()
                 && c.id.to_string() == dotenv::var("DEFAULT_CRT_ID").unwrap()
             {
                 trace!("Default cert found! {}", &c.id);
-                self.default_cert = Some(ck.clone());
+                new.default_cert_id = Some(c.id);
             }
 
-            self.cert_id_to_cert_lookup.insert(c.id, ck.clone());
-            for dn in c.domain_names.as_ref().unwrap() {
+            new.cert_id_to_cert_lookup.insert(c.id, loaded);
+            new.cert_id_to_pem_lookup.insert(c.id, pem);
+            for dn in c.domain_names.iter().flatten() {
                 trace!("{} mapping {} => {} ", c.id, dn.dn, c.forward);
-                self.name_to_cert_id_lookup.insert(dn.dn.clone(), c.id);
+                if !new.name_to_cert_id_lookup.push(&dn.dn, c.id) {
+                    warn!(target: "0","Certificate {} has an invalid name {}, ignoring it",c.id,dn.dn);
+                }
             }
         }
+        let removed = old
+            .cert_id_to_cert_lookup
+            .keys()
+            .filter(|id| !new.cert_id_to_cert_lookup.contains_key(id))
+            .count();
+        debug!(target: "0","Certificates populated, {} new or changed, {} unchanged, {} removed",parsed,reused,removed);
+        self.state.store(Arc::new(new));
     }
-}
 
-impl ResolvesServerCert for MariaSNIResolver {
-    fn resolve(&self, client_hello: rustls::ClientHello) -> Option<sign::CertifiedKey> {
-        if client_hello.server_name().is_none() {
-            trace!("Can't lookup db certificate: no SNI from session");
-            return self.default_cert.clone();
+    pub fn expiry(&self) -> Vec<CertificateExpiry> {
+        let state = self.state.load();
+        let mut expiry = Vec::new();
+        for (name, ids) in state.name_to_cert_id_lookup.iter() {
+            for loaded in ids
+                .iter()
+                .filter_map(|id| state.cert_id_to_cert_lookup.get(id))
+            {
+                expiry.push(CertificateExpiry::new(&name, loaded));
+            }
         }
-        let name: &str = client_hello.server_name().unwrap().into();
-        trace!(
-            "trying to resolve name: {:?} for signature scheme: {:?}",
-            name,
-            client_hello.sigschemes()
-        );
-        let opt_id = self.name_to_cert_id_lookup.get(name);
-        if opt_id.is_some() {
-            let id = opt_id.unwrap();
-            let opt_ck = self.cert_id_to_cert_lookup.get(id).clone();
-            if opt_ck.is_some() {
-                trace!("Resolved name {} as CK success", &name);
-                Some(opt_ck.unwrap().clone())
-            } else {
-                return self.default_cert.clone();
+        expiry
+    }
+
+    // Every certificate once, with all the names it is served for.
+    pub fn certificates(&self) -> Vec<PluginCertificate> {
+        let state = self.state.load();
+        let mut names: collections::HashMap<i32, Vec<String>> = collections::HashMap::new();
+        for (name, ids) in state.name_to_cert_id_lookup.iter() {
+            for id in ids {
+                names.entry(*id).or_default().push(name.clone());
             }
-        } else {
-            return self.default_cert.clone();
         }
+        state
+            .cert_id_to_cert_lookup
+            .iter()
+            .map(|(id, loaded)| PluginCertificate {
+                names: names.remove(id).unwrap_or_default(),
+                fullchain: String::from(&*loaded.fullchain),
+                privkey: String::from(&*loaded.privkey),
+                default: state.default_cert_id == Some(*id),
+            })
+            .collect()
     }
 }
@@ -0,0 +1,171 @@
+use arc_swap::ArcSwap;
+use interfaces::{CertificateExpiry, ClientAuthPolicy, LoadSummary, PluginCertificate};
+#[allow(unused_imports)]
+use logg::{debug, error, info, trace, warn};
+use mysql::prelude::Queryable;
+use std::{
+    collections::HashMap,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::cert_database::{connect, get_all_certificates, Certificate, MariaCertificates};
+
+// Seconds between looking for changes in the database, 0 turns the watcher off.
+const DEFAULT_POLL_INTERVAL: u64 = 10;
+// Any query will do as long as its result changes when the certificates do.
+const DEFAULT_CHANGE_MARKER: &str = "CHECKSUM TABLE certificate, cert_domainname";
+// With SELECT_CLIENT_AUTH the policies are watched too.
+const DEFAULT_CLIENT_AUTH_CHANGE_MARKER: &str =
+    "CHECKSUM TABLE certificate, cert_domainname, cert_client_auth";
+
+// The certificates and forwards from the database, shared between the plugin and the
+// watcher thread. The proxy picks up new certificates when generation changes.
+pub struct CertificateStore {
+    certificates: MariaCertificates,
+    forwards: ArcSwap<HashMap<String, String>>,
+    client_auth: ArcSwap<HashMap<String, ClientAuthPolicy>>,
+    generation: AtomicU64,
+    summary: ArcSwap<LoadSummary>,
+}
+
+impl CertificateStore {
+    pub fn load() -> Result<CertificateStore, String> {
+        let store = CertificateStore {
+            certificates: MariaCertificates::new(),
+            forwards: ArcSwap::from_pointee(HashMap::new()),
+            client_auth: ArcSwap::from_pointee(HashMap::new()),
+            generation: AtomicU64::new(0),
+            summary: ArcSwap::from_pointee(LoadSummary::default()),
+        };
+        store.refresh()?;
+        Ok(store)
+    }
+
+    // Reads everything from the database again and publishes it.
+    pub fn refresh(&self) -> Result<(), String> {
+        let mut summary = LoadSummary::default();
+        let certificates = get_all_certificates(&mut summary)?;
+        self.certificates.populate(&certificates, &mut summary);
+        self.forwards.store(Arc::new(forwards_from(&certificates)));
+        self.client_auth
+            .store(Arc::new(client_auth_from(&certificates)));
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        info!(target: "0","Loaded {} certificates from database, {} skipped, {} expired",summary.loaded,summary.skipped,summary.expired);
+        self.summary.store(Arc::new(summary));
+        Ok(())
+    }
+
+    pub fn certificates(&self) -> Vec<PluginCertificate> {
+        self.certificates.certificates()
+    }
+
+    pub fn forwards(&self) -> Arc<HashMap<String, String>> {
+        self.forwards.load_full()
+    }
+
+    pub fn client_auth(&self) -> Arc<HashMap<String, ClientAuthPolicy>> {
+        self.client_auth.load_full()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn summary(&self) -> LoadSummary {
+        LoadSummary::clone(&self.summary.load())
+    }
+
+    pub fn expiry(&self) -> Vec<CertificateExpiry> {
+        self.certificates.expiry()
+    }
+}
+
+fn forwards_from(certificates: &[Certificate]) -> HashMap<String, String> {
+    let mut forwards: HashMap<String, String> = HashMap::new();
+    for cert in certificates.iter().filter(|c| !c.forward.contains("127")) {
+        if cert.domain_names.is_some() {
+            for dn in cert.domain_names.as_ref().unwrap() {
+                forwards.insert(String::from(&dn.dn), String::from(&cert.forward));
+            }
+        }
+    }
+    forwards
+}
+
+fn client_auth_from(certificates: &[Certificate]) -> HashMap<String, ClientAuthPolicy> {
+    let mut client_auth: HashMap<String, ClientAuthPolicy> = HashMap::new();
+    for cert in certificates {
+        if let Some(policy) = cert.client_auth.as_ref() {
+            for dn in cert.domain_names.iter().flatten() {
+                client_auth.insert(String::from(&dn.dn), policy.clone());
+            }
+        }
+    }
+    client_auth
+}
+
+// Starts a thread that polls SELECT_CRT_CHANGE_MARKER every CERT_POLL_INTERVAL seconds
+// and refreshes the store when the result changes, so rows added by a certbot hook
+// are served without a restart.
+pub fn watch_for_changes(store: Arc<CertificateStore>) {
+    let interval: u64 = dotenv::var("CERT_POLL_INTERVAL")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(DEFAULT_POLL_INTERVAL);
+    if interval == 0 {
+        info!(target: "0","CERT_POLL_INTERVAL is 0, not watching the database for changes");
+        return;
+    }
+    let query = dotenv::var("SELECT_CRT_CHANGE_MARKER").unwrap_or_else(|_| {
+        if dotenv::var("SELECT_CLIENT_AUTH").is_ok() {
+            String::from(DEFAULT_CLIENT_AUTH_CHANGE_MARKER)
+        } else {
+            String::from(DEFAULT_CHANGE_MARKER)
+        }
+    });
+
+    info!(target: "0","Watching the database for certificate changes every {}s",interval);
+    thread::spawn(move || {
+        let mut last_marker = change_marker(&query).ok();
+        loop {
+            thread::sleep(Duration::from_secs(interval));
+            let marker = match change_marker(&query) {
+                Ok(marker) => marker,
+                Err(e) => {
+                    warn!(target: "0","Unable to check for certificate changes: {}",e);
+                    continue;
+                }
+            };
+            if last_marker.as_ref() == Some(&marker) {
+                continue;
+            }
+            info!(target: "0","Certificates changed in the database, refreshing");
+            //A broken row must not take the watcher down with it.
+            match catch_unwind(AssertUnwindSafe(|| store.refresh())) {
+                Ok(Ok(())) => last_marker = Some(marker),
+                Ok(Err(e)) => error!(target: "0","Refreshing certificates failed: {}",e),
+                Err(_) => {
+                    error!(target: "0","Refreshing certificates panicked, keeping the current ones")
+                }
+            }
+        }
+    });
+}
+
+fn change_marker(query: &str) -> Result<String, String> {
+    let mut conn = connect()?;
+    let rows: Vec<mysql::Row> = conn
+        .query(query)
+        .map_err(|e| format!("Error selecting change marker: {:?}", e))?;
+    Ok(rows
+        .into_iter()
+        .map(|row| format!("{:?}", row.unwrap()))
+        .collect::<Vec<String>>()
+        .join(";"))
+}
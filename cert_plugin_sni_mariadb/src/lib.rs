@@ -1,66 +1,81 @@
 //extern crate log as logg;
 mod cert_database;
+mod cert_watcher;
 mod env_logger;
-use interfaces::CertificateHandler;
+use interfaces::{
+    CertificateExpiry, CertificateHandler, ClientAuthPolicy, LoadSummary, PluginCertificate,
+};
 
-use cert_database::{get_all_certificates, Certificate, MariaSNIResolver};
+use cert_database::insert_certificate;
+use cert_watcher::{watch_for_changes, CertificateStore};
 use env_logger::activate_env_logger;
 #[allow(unused_imports)]
 use logg::{debug, error, info, trace, warn};
 use std::{collections::HashMap, sync::Arc};
 
-#[no_mangle]
-pub fn get_certificate_handler() -> Box<dyn CertificateHandler> {
+interfaces::declare_certificate_handler_plugin!(get_certificate_handler);
+
+pub fn get_certificate_handler() -> Result<Box<dyn CertificateHandler>, String> {
     activate_env_logger();
-    Box::new(CH::new())
+    Ok(Box::new(CH::new()?))
 }
 #[allow(dead_code)]
 pub struct CH {
     id: String,
-    import_certificates: Vec<Certificate>,
+    store: Arc<CertificateStore>,
 }
 
 impl CH {
-    fn new() -> CH {
+    fn new() -> Result<CH, String> {
         let id = format!("{:08x}", rand::random::<u32>());
-        println!("[{}] Created instance!", id);
-        let import_certificates = get_all_certificates();
-        CH {
-            id,
-            import_certificates,
-        }
+        debug!(target: "0","[{}] Created certificate plugin instance",id);
+        let store =
+            Arc::new(CertificateStore::load().map_err(|e| format!("Loading certificates: {}", e))?);
+        watch_for_changes(store.clone());
+        Ok(CH { id, store })
     }
 }
 
 impl CertificateHandler for CH {
     fn get_forwards(&self) -> Box<Arc<HashMap<String, String>>> {
         info!(target: "0","Load forwards from database");
-        let import_certificates = &self.import_certificates;
-        let mut forwards: HashMap<String, String> = HashMap::new();
-        for cert in import_certificates
-            .iter()
-            .filter(|c| !c.forward.contains("127"))
-        {
-            if cert.domain_names.is_some() {
-                for dn in cert.domain_names.as_ref().unwrap() {
-                    if None == forwards.insert(String::from(&dn.dn), String::from(&cert.forward)) {
-                        //Hosts println!("127.0.0.1   {0}  # {0}  => {1}",dn.dn,cert.forward);
-                    }
-                }
-            }
-        }
-        info!(target: "0","Forwards done");
-        Box::new(Arc::new(forwards.clone()))
+        Box::new(self.store.forwards())
+    }
+
+    fn certificates(&self) -> Vec<PluginCertificate> {
+        self.store.certificates()
+    }
+
+    fn reload(&mut self) -> Result<(), String> {
+        info!(target: "0","Reloading certificates from database");
+        self.store.refresh()
+    }
+
+    fn generation(&self) -> u64 {
+        self.store.generation()
+    }
+
+    fn load_summary(&self) -> LoadSummary {
+        self.store.summary()
     }
 
-    fn get_sni_resolver(&self) -> Box<Arc<dyn rustls::ResolvesServerCert>> {
-        info!(target: "0","Resolver");
-        let import_certificates = &self.import_certificates;
-        let mut resolver = MariaSNIResolver::new();
-        resolver.populate(&import_certificates);
-        let resolver = resolver;
+    fn certificate_expiry(&self) -> Vec<CertificateExpiry> {
+        self.store.expiry()
+    }
+
+    fn store_certificate(
+        &mut self,
+        names: &[String],
+        forward: &str,
+        fullchain: &str,
+        privkey: &str,
+    ) -> Result<(), String> {
+        insert_certificate(names, forward, fullchain, privkey)?;
+        //Served right away instead of at the next CERT_POLL_INTERVAL.
+        self.store.refresh()
+    }
 
-        info!(target: "0","Resolver done");
-        Box::new(Arc::new(resolver).clone())
+    fn client_auth(&self) -> HashMap<String, ClientAuthPolicy> {
+        HashMap::clone(&self.store.client_auth())
     }
 }
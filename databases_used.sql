@@ -16,3 +16,11 @@ CREATE TABLE `certificate` (
 	PRIMARY KEY (`id`)
 )
 COLLATE='utf8mb4_swedish_ci';
+
+CREATE TABLE `cert_client_auth` (
+	`cert_id` INT UNSIGNED NOT NULL COMMENT 'The certificate whose names ask for client certificates',
+	`client_auth` ENUM('none','optional','required') NOT NULL DEFAULT 'required' COMMENT 'Whether a client certificate is needed',
+	`client_ca` TEXT NOT NULL COMMENT 'The CA certificates client certificates must be issued by, PEM',
+	PRIMARY KEY (`cert_id`)
+)
+COLLATE='utf8mb4_swedish_ci';
@@ -0,0 +1,55 @@
+use rustls::internal::pemfile::certs;
+use std::io::Cursor;
+
+// Whether a host asks TLS clients for a certificate, and if it has to have one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuthMode {
+    None,
+    Optional,
+    Required,
+}
+
+impl ClientAuthMode {
+    pub fn parse(mode: &str) -> Option<ClientAuthMode> {
+        match mode.trim().to_lowercase().as_str() {
+            "none" => Some(ClientAuthMode::None),
+            "optional" => Some(ClientAuthMode::Optional),
+            "required" => Some(ClientAuthMode::Required),
+            _ => None,
+        }
+    }
+}
+
+// The client certificate policy the plugin keeps with a certificate. Client certificates
+// must be issued by one of ca_certificates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientAuthPolicy {
+    pub mode: ClientAuthMode,
+    pub ca_certificates: Vec<rustls::Certificate>,
+}
+
+impl ClientAuthPolicy {
+    // From a mode as ClientAuthMode::parse takes it and the CA bundle as PEM.
+    pub fn from_pem(mode: &str, ca_pem: &str) -> Result<ClientAuthPolicy, String> {
+        let mode = ClientAuthMode::parse(mode)
+            .ok_or_else(|| format!("unknown client auth mode {:?}", mode.trim()))?;
+        let ca_certificates = certs(&mut Cursor::new(ca_pem))
+            .map_err(|_| String::from("unable to parse the client CA certificates"))?;
+        if mode != ClientAuthMode::None && ca_certificates.is_empty() {
+            return Err(String::from("no client CA certificates"));
+        }
+        Ok(ClientAuthPolicy {
+            mode,
+            ca_certificates,
+        })
+    }
+
+    // Required without a CA, no client gets in. For hosts whose policy can't be read, they
+    // must not be served without one.
+    pub fn deny_all() -> ClientAuthPolicy {
+        ClientAuthPolicy {
+            mode: ClientAuthMode::Required,
+            ca_certificates: Vec::new(),
+        }
+    }
+}
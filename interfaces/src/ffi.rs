@@ -0,0 +1,707 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    mem::MaybeUninit,
+    os::raw::c_void,
+    panic::{catch_unwind, AssertUnwindSafe},
+    slice,
+};
+
+use crate::{
+    Cacher, CertificateExpiry, CertificateHandler, ClientAuthMode, ClientAuthPolicy, LoadSummary,
+    PluginCertificate,
+};
+
+// What crosses the plugin boundary: repr(C) structs of extern "C" functions, pointers to
+// bytes and integers. Everything else is encoded into bytes by the side that has it and
+// decoded by the other, see Wire. Each side only ever frees what it allocated itself,
+// answers are written through an FfiOut into a buffer of the caller.
+
+// Borrowed bytes, only valid during the call they are passed to.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FfiSlice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl FfiSlice {
+    pub fn new(bytes: &[u8]) -> FfiSlice {
+        FfiSlice {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+
+    // Only as long as what it was made from lives.
+    unsafe fn as_bytes<'a>(self) -> &'a [u8] {
+        if self.ptr.is_null() || self.len == 0 {
+            &[]
+        } else {
+            slice::from_raw_parts(self.ptr, self.len)
+        }
+    }
+
+    unsafe fn to_string(self) -> String {
+        String::from_utf8_lossy(self.as_bytes()).into_owned()
+    }
+}
+
+// Where a plugin writes an answer, write copies the bytes into the caller's buffer.
+#[repr(C)]
+pub struct FfiOut {
+    pub context: *mut c_void,
+    pub write: extern "C" fn(context: *mut c_void, bytes: FfiSlice),
+}
+
+impl FfiOut {
+    // The buffer has to outlive the call the FfiOut is passed to.
+    pub fn to_vec(buffer: &mut Vec<u8>) -> FfiOut {
+        FfiOut {
+            context: buffer as *mut Vec<u8> as *mut c_void,
+            write: write_to_vec,
+        }
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        (self.write)(self.context, FfiSlice::new(bytes))
+    }
+}
+
+extern "C" fn write_to_vec(context: *mut c_void, bytes: FfiSlice) {
+    let buffer = unsafe { &mut *(context as *mut Vec<u8>) };
+    buffer.extend_from_slice(unsafe { bytes.as_bytes() });
+}
+
+// What the functions behind the boundary return.
+pub const FFI_OK: i32 = 0;
+// The answer is an error message.
+pub const FFI_ERROR: i32 = 1;
+// The plugin panicked, there is no answer.
+pub const FFI_PANIC: i32 = 2;
+
+// Runs the plugin end of a call, the answer or the error goes to out and a panic stays on
+// the plugin's side of the boundary.
+fn answer<F: FnOnce(&mut Encoder) -> Result<(), String>>(out: &FfiOut, call: F) -> i32 {
+    let mut encoder = Encoder::default();
+    match catch_unwind(AssertUnwindSafe(|| call(&mut encoder))) {
+        Ok(Ok(())) => {
+            out.write(&encoder.bytes);
+            FFI_OK
+        }
+        Ok(Err(e)) => {
+            out.write(e.as_bytes());
+            FFI_ERROR
+        }
+        Err(_) => FFI_PANIC,
+    }
+}
+
+// The caller's end, decodes what call answered.
+pub fn call<T: Wire, F: FnOnce(FfiOut) -> i32>(name: &str, function: F) -> Result<T, String> {
+    let mut buffer = Vec::new();
+    match function(FfiOut::to_vec(&mut buffer)) {
+        FFI_OK => {
+            let mut decoder = Decoder::new(&buffer);
+            T::decode(&mut decoder)
+                .map_err(|e| format!("unable to read the answer to {}: {}", name, e))
+        }
+        FFI_ERROR => Err(String::from_utf8_lossy(&buffer).into_owned()),
+        FFI_PANIC => Err(format!("the plugin panicked in {}", name)),
+        status => Err(format!("{} returned unknown status {}", name, status)),
+    }
+}
+
+// What a plugin constructor declared with declare_*_plugin! does, out is only written on
+// FFI_OK.
+pub fn export<T, F: FnOnce() -> Result<T, String>>(
+    out: &mut MaybeUninit<T>,
+    error: FfiOut,
+    create: F,
+) -> i32 {
+    answer(&error, |_| {
+        *out = MaybeUninit::new(create()?);
+        Ok(())
+    })
+}
+
+#[derive(Default)]
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder { bytes }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err(String::from("truncated"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut value = [0u8; 8];
+        value.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        let len = usize::try_from(self.u64()?).map_err(|_| String::from("too long"))?;
+        //Every element is at least one byte, a bigger count can only be garbage.
+        if len > self.bytes.len() {
+            return Err(String::from("truncated"));
+        }
+        Ok(len)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.len()?;
+        self.take(len)
+    }
+}
+
+// How a value is encoded for the other side of the boundary, the same on both sides as
+// long as they were built against the same PLUGIN_ABI_VERSION.
+pub trait Wire: Sized {
+    fn encode(&self, encoder: &mut Encoder);
+    fn decode(decoder: &mut Decoder) -> Result<Self, String>;
+}
+
+impl Wire for () {
+    fn encode(&self, _encoder: &mut Encoder) {}
+
+    fn decode(_decoder: &mut Decoder) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Wire for u64 {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u64(*self);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<u64, String> {
+        decoder.u64()
+    }
+}
+
+impl Wire for i64 {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u64(*self as u64);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<i64, String> {
+        Ok(decoder.u64()? as i64)
+    }
+}
+
+impl Wire for usize {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u64(*self as u64);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<usize, String> {
+        usize::try_from(decoder.u64()?).map_err(|_| String::from("too big"))
+    }
+}
+
+impl Wire for bool {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u64(*self as u64);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<bool, String> {
+        Ok(decoder.u64()? != 0)
+    }
+}
+
+impl Wire for String {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.bytes(self.as_bytes());
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<String, String> {
+        String::from_utf8(decoder.bytes()?.to_vec()).map_err(|_| String::from("not UTF-8"))
+    }
+}
+
+impl<T: Wire> Wire for Vec<T> {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u64(self.len() as u64);
+        for value in self {
+            value.encode(encoder);
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Vec<T>, String> {
+        let len = decoder.len()?;
+        let mut values = Vec::with_capacity(len);
+        for _ in 0..len {
+            values.push(T::decode(decoder)?);
+        }
+        Ok(values)
+    }
+}
+
+impl<T: Wire> Wire for Option<T> {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.is_some().encode(encoder);
+        if let Some(value) = self {
+            value.encode(encoder);
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Option<T>, String> {
+        if bool::decode(decoder)? {
+            Ok(Some(T::decode(decoder)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: Wire> Wire for HashMap<String, T> {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u64(self.len() as u64);
+        for (key, value) in self {
+            key.encode(encoder);
+            value.encode(encoder);
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<HashMap<String, T>, String> {
+        let len = decoder.len()?;
+        let mut map = HashMap::with_capacity(len);
+        for _ in 0..len {
+            let key = String::decode(decoder)?;
+            map.insert(key, T::decode(decoder)?);
+        }
+        Ok(map)
+    }
+}
+
+// Vec<u8> would go byte by byte as a Vec<T>.
+pub struct Bytes(pub Vec<u8>);
+
+impl Wire for Bytes {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.bytes(&self.0);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Bytes, String> {
+        Ok(Bytes(decoder.bytes()?.to_vec()))
+    }
+}
+
+impl Wire for PluginCertificate {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.names.encode(encoder);
+        self.fullchain.encode(encoder);
+        self.privkey.encode(encoder);
+        self.default.encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<PluginCertificate, String> {
+        Ok(PluginCertificate {
+            names: Wire::decode(decoder)?,
+            fullchain: Wire::decode(decoder)?,
+            privkey: Wire::decode(decoder)?,
+            default: Wire::decode(decoder)?,
+        })
+    }
+}
+
+impl Wire for CertificateExpiry {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.name.encode(encoder);
+        self.not_before.encode(encoder);
+        self.not_after.encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<CertificateExpiry, String> {
+        Ok(CertificateExpiry {
+            name: Wire::decode(decoder)?,
+            not_before: Wire::decode(decoder)?,
+            not_after: Wire::decode(decoder)?,
+        })
+    }
+}
+
+impl Wire for LoadSummary {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.loaded.encode(encoder);
+        self.skipped.encode(encoder);
+        self.expired.encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<LoadSummary, String> {
+        Ok(LoadSummary {
+            loaded: Wire::decode(decoder)?,
+            skipped: Wire::decode(decoder)?,
+            expired: Wire::decode(decoder)?,
+        })
+    }
+}
+
+impl Wire for ClientAuthPolicy {
+    fn encode(&self, encoder: &mut Encoder) {
+        let mode: u64 = match self.mode {
+            ClientAuthMode::None => 0,
+            ClientAuthMode::Optional => 1,
+            ClientAuthMode::Required => 2,
+        };
+        mode.encode(encoder);
+        encoder.u64(self.ca_certificates.len() as u64);
+        for certificate in &self.ca_certificates {
+            encoder.bytes(&certificate.0);
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<ClientAuthPolicy, String> {
+        let mode = match decoder.u64()? {
+            0 => ClientAuthMode::None,
+            1 => ClientAuthMode::Optional,
+            2 => ClientAuthMode::Required,
+            mode => return Err(format!("unknown client auth mode {}", mode)),
+        };
+        let len = decoder.len()?;
+        let mut ca_certificates = Vec::with_capacity(len);
+        for _ in 0..len {
+            ca_certificates.push(rustls::Certificate(decoder.bytes()?.to_vec()));
+        }
+        Ok(ClientAuthPolicy {
+            mode,
+            ca_certificates,
+        })
+    }
+}
+
+// A CertificateHandler as the proxy sees it. this is the plugin's handler, only the
+// functions next to it touch it. Answers are encoded as Wire, requests come as FfiSlices.
+#[repr(C)]
+pub struct FfiCertificateHandler {
+    pub this: *mut c_void,
+    pub drop: extern "C" fn(this: *mut c_void),
+    // HashMap<String, String>
+    pub forwards: extern "C" fn(this: *mut c_void, out: FfiOut) -> i32,
+    // Vec<PluginCertificate>
+    pub certificates: extern "C" fn(this: *mut c_void, out: FfiOut) -> i32,
+    // ()
+    pub reload: extern "C" fn(this: *mut c_void, out: FfiOut) -> i32,
+    // u64
+    pub generation: extern "C" fn(this: *mut c_void, out: FfiOut) -> i32,
+    // LoadSummary
+    pub load_summary: extern "C" fn(this: *mut c_void, out: FfiOut) -> i32,
+    // Vec<CertificateExpiry>
+    pub certificate_expiry: extern "C" fn(this: *mut c_void, out: FfiOut) -> i32,
+    // names is a Vec<String>, answers ()
+    pub store_certificate: extern "C" fn(
+        this: *mut c_void,
+        names: FfiSlice,
+        forward: FfiSlice,
+        fullchain: FfiSlice,
+        privkey: FfiSlice,
+        out: FfiOut,
+    ) -> i32,
+    // HashMap<String, ClientAuthPolicy>
+    pub client_auth: extern "C" fn(this: *mut c_void, out: FfiOut) -> i32,
+}
+
+impl FfiCertificateHandler {
+    // The plugin's end, made by declare_certificate_handler_plugin!.
+    pub fn new(handler: Box<dyn CertificateHandler>) -> FfiCertificateHandler {
+        FfiCertificateHandler {
+            this: Box::into_raw(Box::new(handler)) as *mut c_void,
+            drop: handler_drop,
+            forwards: handler_forwards,
+            certificates: handler_certificates,
+            reload: handler_reload,
+            generation: handler_generation,
+            load_summary: handler_load_summary,
+            certificate_expiry: handler_certificate_expiry,
+            store_certificate: handler_store_certificate,
+            client_auth: handler_client_auth,
+        }
+    }
+}
+
+unsafe fn handler<'a>(this: *mut c_void) -> &'a mut Box<dyn CertificateHandler> {
+    &mut *(this as *mut Box<dyn CertificateHandler>)
+}
+
+extern "C" fn handler_drop(this: *mut c_void) {
+    let _ =
+        catch_unwind(|| drop(unsafe { Box::from_raw(this as *mut Box<dyn CertificateHandler>) }));
+}
+
+extern "C" fn handler_forwards(this: *mut c_void, out: FfiOut) -> i32 {
+    answer(&out, |encoder| {
+        let forwards = unsafe { handler(this) }.get_forwards();
+        HashMap::clone(&forwards).encode(encoder);
+        Ok(())
+    })
+}
+
+extern "C" fn handler_certificates(this: *mut c_void, out: FfiOut) -> i32 {
+    answer(&out, |encoder| {
+        unsafe { handler(this) }.certificates().encode(encoder);
+        Ok(())
+    })
+}
+
+extern "C" fn handler_reload(this: *mut c_void, out: FfiOut) -> i32 {
+    answer(&out, |_| unsafe { handler(this) }.reload())
+}
+
+extern "C" fn handler_generation(this: *mut c_void, out: FfiOut) -> i32 {
+    answer(&out, |encoder| {
+        unsafe { handler(this) }.generation().encode(encoder);
+        Ok(())
+    })
+}
+
+extern "C" fn handler_load_summary(this: *mut c_void, out: FfiOut) -> i32 {
+    answer(&out, |encoder| {
+        unsafe { handler(this) }.load_summary().encode(encoder);
+        Ok(())
+    })
+}
+
+extern "C" fn handler_certificate_expiry(this: *mut c_void, out: FfiOut) -> i32 {
+    answer(&out, |encoder| {
+        unsafe { handler(this) }
+            .certificate_expiry()
+            .encode(encoder);
+        Ok(())
+    })
+}
+
+extern "C" fn handler_store_certificate(
+    this: *mut c_void,
+    names: FfiSlice,
+    forward: FfiSlice,
+    fullchain: FfiSlice,
+    privkey: FfiSlice,
+    out: FfiOut,
+) -> i32 {
+    answer(&out, |_| {
+        let names: Vec<String> = Wire::decode(&mut Decoder::new(unsafe { names.as_bytes() }))?;
+        let (forward, fullchain, privkey) = unsafe {
+            (
+                forward.to_string(),
+                fullchain.to_string(),
+                privkey.to_string(),
+            )
+        };
+        unsafe { handler(this) }.store_certificate(&names, &forward, &fullchain, &privkey)
+    })
+}
+
+extern "C" fn handler_client_auth(this: *mut c_void, out: FfiOut) -> i32 {
+    answer(&out, |encoder| {
+        unsafe { handler(this) }.client_auth().encode(encoder);
+        Ok(())
+    })
+}
+
+// A Cacher as the proxy sees it, like FfiCertificateHandler.
+#[repr(C)]
+pub struct FfiCacher {
+    pub this: *mut c_void,
+    pub drop: extern "C" fn(this: *mut c_void),
+    // bool
+    pub update: extern "C" fn(
+        this: *mut c_void,
+        host: FfiSlice,
+        forward: FfiSlice,
+        http_path: FfiSlice,
+        data: FfiSlice,
+        out: FfiOut,
+    ) -> i32,
+    // Option<Bytes>
+    pub read:
+        extern "C" fn(this: *mut c_void, host: FfiSlice, http_path: FfiSlice, out: FfiOut) -> i32,
+}
+
+impl FfiCacher {
+    // The plugin's end, made by declare_cacher_plugin!.
+    pub fn new(cacher: Box<dyn Cacher>) -> FfiCacher {
+        FfiCacher {
+            this: Box::into_raw(Box::new(cacher)) as *mut c_void,
+            drop: cacher_drop,
+            update: cacher_update,
+            read: cacher_read,
+        }
+    }
+}
+
+unsafe fn cacher<'a>(this: *mut c_void) -> &'a mut Box<dyn Cacher> {
+    &mut *(this as *mut Box<dyn Cacher>)
+}
+
+extern "C" fn cacher_drop(this: *mut c_void) {
+    let _ = catch_unwind(|| drop(unsafe { Box::from_raw(this as *mut Box<dyn Cacher>) }));
+}
+
+extern "C" fn cacher_update(
+    this: *mut c_void,
+    host: FfiSlice,
+    forward: FfiSlice,
+    http_path: FfiSlice,
+    data: FfiSlice,
+    out: FfiOut,
+) -> i32 {
+    answer(&out, |encoder| {
+        let (host, forward, http_path) =
+            unsafe { (host.to_string(), forward.to_string(), http_path.to_string()) };
+        let data = unsafe { data.as_bytes() }.to_vec();
+        unsafe { cacher(this) }
+            .cache_update_and_test_path(&host, &forward, &http_path, &data)
+            .map_err(|e| e.to_string())?
+            .encode(encoder);
+        Ok(())
+    })
+}
+
+extern "C" fn cacher_read(
+    this: *mut c_void,
+    host: FfiSlice,
+    http_path: FfiSlice,
+    out: FfiOut,
+) -> i32 {
+    answer(&out, |encoder| {
+        let (host, http_path) = unsafe { (host.to_string(), http_path.to_string()) };
+        unsafe { cacher(this) }
+            .cache_read_path(&host, &http_path)
+            .map(Bytes)
+            .encode(encoder);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Wire>(value: &T) -> T {
+        let mut encoder = Encoder::default();
+        value.encode(&mut encoder);
+        let bytes = encoder.into_bytes();
+        let mut decoder = Decoder::new(&bytes);
+        let decoded = T::decode(&mut decoder).unwrap();
+        assert!(decoder.bytes.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn values_survive_the_boundary() {
+        let certificates = vec![PluginCertificate {
+            names: vec![String::from("a.test"), String::from("*.a.test")],
+            fullchain: String::from("chain"),
+            privkey: String::from("key"),
+            default: true,
+        }];
+        assert_eq!(round_trip(&certificates), certificates);
+        let mut client_auth = HashMap::new();
+        client_auth.insert(
+            String::from("a.test"),
+            ClientAuthPolicy {
+                mode: ClientAuthMode::Optional,
+                ca_certificates: vec![rustls::Certificate(vec![1, 2, 3])],
+            },
+        );
+        assert_eq!(round_trip(&client_auth), client_auth);
+        let expiry = vec![CertificateExpiry {
+            name: String::from("a.test"),
+            not_before: -1,
+            not_after: 1_700_000_000,
+        }];
+        assert_eq!(round_trip(&expiry), expiry);
+        assert_eq!(
+            round_trip(&Some(Bytes(vec![0, 255]))).map(|b| b.0),
+            Some(vec![0, 255])
+        );
+    }
+
+    struct TestHandler;
+
+    impl CertificateHandler for TestHandler {
+        fn get_forwards(&self) -> Box<std::sync::Arc<HashMap<String, String>>> {
+            let mut forwards = HashMap::new();
+            forwards.insert(String::from("a.test"), String::from("127.0.0.1:8080"));
+            Box::new(std::sync::Arc::new(forwards))
+        }
+
+        fn certificates(&self) -> Vec<PluginCertificate> {
+            Vec::new()
+        }
+
+        fn reload(&mut self) -> Result<(), String> {
+            panic!("reload")
+        }
+    }
+
+    #[test]
+    fn calls_through_the_vtable() {
+        let ffi = FfiCertificateHandler::new(Box::new(TestHandler));
+        let forwards: HashMap<String, String> =
+            call("forwards", |out| (ffi.forwards)(ffi.this, out)).unwrap();
+        assert_eq!(forwards["a.test"], "127.0.0.1:8080");
+        let generation: u64 = call("generation", |out| (ffi.generation)(ffi.this, out)).unwrap();
+        assert_eq!(generation, 0);
+        assert_eq!(
+            call::<(), _>("reload", |out| (ffi.reload)(ffi.this, out)),
+            Err(String::from("the plugin panicked in reload"))
+        );
+        let mut names = Encoder::default();
+        vec![String::from("a.test")].encode(&mut names);
+        let names = names.into_bytes();
+        let stored = call::<(), _>("store_certificate", |out| {
+            let pem = FfiSlice::new(b"pem");
+            (ffi.store_certificate)(ffi.this, FfiSlice::new(&names), pem, pem, pem, out)
+        });
+        assert_eq!(
+            stored,
+            Err(String::from(
+                "This certificate plugin can not store certificates"
+            ))
+        );
+        (ffi.drop)(ffi.this);
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        let mut encoder = Encoder::default();
+        vec![String::from("a.test")].encode(&mut encoder);
+        let bytes = encoder.into_bytes();
+        for len in 0..bytes.len() {
+            assert!(Vec::<String>::decode(&mut Decoder::new(&bytes[..len])).is_err());
+        }
+        let huge = u64::MAX.to_le_bytes();
+        assert!(Vec::<String>::decode(&mut Decoder::new(&huge)).is_err());
+    }
+}
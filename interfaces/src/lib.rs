@@ -1,8 +1,171 @@
 use std::{collections::HashMap, sync::Arc};
 
+mod certified_key;
+mod client_auth;
+pub mod ffi;
+mod sni_names;
+pub use certified_key::{
+    certificate_validity, certified_key_from_pem, choose_certified_key, unix_now,
+    CertificateError, LoadedCertificate,
+};
+pub use client_auth::{ClientAuthMode, ClientAuthPolicy};
+pub use sni_names::SniNames;
+
+// Plugin ABI
+//
+// A plugin is a dylib exporting two extern "C" functions, use declare_cacher_plugin! or
+// declare_certificate_handler_plugin! to get them right:
+//
+//   sni_plugin_abi_version()          -> u32, checked before anything else.
+//   sni_plugin_cacher(*mut FfiCacher, FfiOut) -> i32
+//   sni_plugin_certificate_handler(*mut FfiCertificateHandler, FfiOut) -> i32
+//
+// The constructors write the plugin into the pointer, or an error message to the FfiOut.
+//
+// Only the repr(C) types in ffi cross the boundary, no Rust trait objects and no rustls
+// types. A plugin can be built on its own, with another compiler or other versions of
+// its dependencies, as long as it has the same PLUGIN_ABI_VERSION.
+
+// Bump when anything in ffi, the traits or the entry points change.
+pub const PLUGIN_ABI_VERSION: u32 = 9;
+
+pub const PLUGIN_ABI_VERSION_SYMBOL: &[u8] = b"sni_plugin_abi_version";
+pub const PLUGIN_CACHER_SYMBOL: &[u8] = b"sni_plugin_cacher";
+pub const PLUGIN_CERTIFICATE_HANDLER_SYMBOL: &[u8] = b"sni_plugin_certificate_handler";
+
+// Exports the entry points for a cache plugin, $constructor is a fn() -> Box<dyn Cacher>.
+#[macro_export]
+macro_rules! declare_cacher_plugin {
+    ($constructor:path) => {
+        #[no_mangle]
+        pub extern "C" fn sni_plugin_abi_version() -> u32 {
+            $crate::PLUGIN_ABI_VERSION
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn sni_plugin_cacher(
+            out: *mut $crate::ffi::FfiCacher,
+            error: $crate::ffi::FfiOut,
+        ) -> i32 {
+            let constructor: fn() -> Box<dyn $crate::Cacher> = $constructor;
+            let out = &mut *(out as *mut ::std::mem::MaybeUninit<$crate::ffi::FfiCacher>);
+            $crate::ffi::export(out, error, || {
+                Ok($crate::ffi::FfiCacher::new(constructor()))
+            })
+        }
+    };
+}
+
+// Exports the entry points for a certificate plugin, $constructor is a
+// fn() -> Result<Box<dyn CertificateHandler>, String>. An Err is logged by the proxy, which
+// then refuses the plugin.
+#[macro_export]
+macro_rules! declare_certificate_handler_plugin {
+    ($constructor:path) => {
+        #[no_mangle]
+        pub extern "C" fn sni_plugin_abi_version() -> u32 {
+            $crate::PLUGIN_ABI_VERSION
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn sni_plugin_certificate_handler(
+            out: *mut $crate::ffi::FfiCertificateHandler,
+            error: $crate::ffi::FfiOut,
+        ) -> i32 {
+            let constructor: fn() -> Result<Box<dyn $crate::CertificateHandler>, String> =
+                $constructor;
+            let out = &mut *(out as *mut ::std::mem::MaybeUninit<$crate::ffi::FfiCertificateHandler>);
+            $crate::ffi::export(out, error, || {
+                Ok($crate::ffi::FfiCertificateHandler::new(constructor()?))
+            })
+        }
+    };
+}
+
 pub trait CertificateHandler {
     fn get_forwards(&self) -> Box<Arc<HashMap<String, String>>>;
-    fn get_sni_resolver(&self) -> Box<Arc<dyn rustls::ResolvesServerCert>>;
+    // The certificates to serve, the proxy makes the keys from the PEMs.
+    fn certificates(&self) -> Vec<PluginCertificate>;
+    // Fetch certificates and forwards again, get_forwards and certificates return
+    // the new ones afterwards. On error the plugin keeps what it had.
+    fn reload(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+    // Changes every time the plugin has new forwards or certificates on its own, e.g. from
+    // a watcher, so the proxy knows to call get_forwards and certificates again.
+    fn generation(&self) -> u64 {
+        0
+    }
+    // How the last load or reload went, for the proxy to log.
+    fn load_summary(&self) -> LoadSummary {
+        LoadSummary::default()
+    }
+    // Every name a certificate is served for and when that certificate is valid.
+    fn certificate_expiry(&self) -> Vec<CertificateExpiry> {
+        Vec::new()
+    }
+    // Keeps a certificate issued by the proxy's ACME client for names, PEM encoded, and
+    // serves it from then on like one the plugin loaded itself. forward is where names
+    // are forwarded now, for backends that store it with the certificate.
+    fn store_certificate(
+        &mut self,
+        _names: &[String],
+        _forward: &str,
+        _fullchain: &str,
+        _privkey: &str,
+    ) -> Result<(), String> {
+        Err(String::from("This certificate plugin can not store certificates"))
+    }
+    // Client certificate policies by certificate name, kept with the certificates. Hosts
+    // without one don't ask for a client certificate.
+    fn client_auth(&self) -> HashMap<String, ClientAuthPolicy> {
+        HashMap::new()
+    }
+}
+
+// A certificate as PEM, served for names. default ones are served to clients that ask
+// for a name no certificate has, or for none.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginCertificate {
+    pub names: Vec<String>,
+    pub fullchain: String,
+    pub privkey: String,
+    pub default: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateExpiry {
+    pub name: String,
+    //Seconds since the epoch.
+    pub not_before: i64,
+    pub not_after: i64,
+}
+
+impl CertificateExpiry {
+    pub fn new(name: &str, certificate: &LoadedCertificate) -> CertificateExpiry {
+        CertificateExpiry {
+            name: String::from(name),
+            not_before: certificate.not_before,
+            not_after: certificate.not_after,
+        }
+    }
+}
+
+// Certificates a plugin loaded, skipped as invalid and skipped as expired.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadSummary {
+    pub loaded: usize,
+    pub skipped: usize,
+    pub expired: usize,
+}
+
+impl LoadSummary {
+    pub fn add_error(&mut self, error: &CertificateError) {
+        match error {
+            CertificateError::Expired { .. } => self.expired += 1,
+            CertificateError::Invalid(_) => self.skipped += 1,
+        }
+    }
 }
 
 
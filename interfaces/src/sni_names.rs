@@ -0,0 +1,106 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+// Certificate names for SNI lookups, shared by the certificate plugins.
+//
+// Names are matched like RFC 6125 says: case insensitive, and a wildcard is only a
+// complete left-most label ("*.example.com") that stands for exactly one label, so it
+// matches shop.example.com but not example.com or a.shop.example.com. Exact names
+// always win over a wildcard.
+#[derive(Debug, Clone)]
+pub struct SniNames<T> {
+    exact: HashMap<String, T>,
+    //Keyed on what follows "*.", e.g. "example.com".
+    wildcard: HashMap<String, T>,
+}
+
+impl<T> Default for SniNames<T> {
+    fn default() -> SniNames<T> {
+        SniNames {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+        }
+    }
+}
+
+impl<T> SniNames<T> {
+    pub fn new() -> SniNames<T> {
+        SniNames::default()
+    }
+
+    // Adds a certificate name, returns false (and ignores it) for wildcards RFC 6125 does
+    // not allow, like "*" alone, "*.com" or "w*.example.com".
+    pub fn insert(&mut self, name: &str, value: T) -> bool {
+        match self.entry(name) {
+            Some(Entry::Occupied(mut entry)) => {
+                entry.insert(value);
+                true
+            }
+            Some(Entry::Vacant(entry)) => {
+                entry.insert(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn entry(&mut self, name: &str) -> Option<Entry<'_, String, T>> {
+        let name = normalize(name);
+        if let Some(parent) = name.strip_prefix("*.") {
+            if parent.contains('*') || !parent.contains('.') {
+                return None;
+            }
+            return Some(self.wildcard.entry(String::from(parent)));
+        }
+        if name.is_empty() || name.contains('*') {
+            return None;
+        }
+        Some(self.exact.entry(name))
+    }
+
+    // Looks up an SNI host name, exact names first and then a wildcard for its parent.
+    pub fn get(&self, server_name: &str) -> Option<&T> {
+        let name = normalize(server_name);
+        if let Some(value) = self.exact.get(&name) {
+            return Some(value);
+        }
+        let (label, parent) = name.split_at(name.find('.')?);
+        if label.is_empty() {
+            return None;
+        }
+        self.wildcard.get(&parent[1..])
+    }
+
+    // All names, wildcards with their "*." put back.
+    pub fn iter(&self) -> impl Iterator<Item = (String, &T)> {
+        self.exact
+            .iter()
+            .map(|(name, value)| (name.clone(), value))
+            .chain(
+                self.wildcard
+                    .iter()
+                    .map(|(parent, value)| (format!("*.{}", parent), value)),
+            )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty()
+    }
+}
+
+// For names with more than one certificate, e.g. both an RSA and an ECDSA one.
+impl<T> SniNames<Vec<T>> {
+    // Like insert but keeps what the name already had, false for invalid names.
+    pub fn push(&mut self, name: &str, value: T) -> bool {
+        match self.entry(name) {
+            Some(entry) => {
+                entry.or_insert_with(Vec::new).push(value);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
@@ -0,0 +1,62 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+// ACME HTTP-01 challenges (RFC 8555 8.3) are answered by the proxy itself, so domains
+// behind it can be validated without every backend having a webroot.
+pub const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+// Key authorizations by token for the challenges our own ACME client has open, its thread
+// adds them and takes them away again.
+pub type AcmeChallenges = Arc<RwLock<HashMap<String, String>>>;
+
+// The token from a challenge path, None if it is not one. Tokens are base64url, anything
+// else could be used to walk out of the webroot.
+pub fn challenge_token(path: &str) -> Option<&str> {
+    let token = path.strip_prefix(ACME_CHALLENGE_PREFIX)?;
+    let token = token.split('?').next().unwrap_or("");
+    if token.is_empty()
+        || !token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return None;
+    }
+    Some(token)
+}
+
+// The key authorization for token, from our own ACME client or else from ACME_WEBROOT,
+// laid out like certbot --webroot writes it: <webroot>/.well-known/acme-challenge/<token>.
+pub fn key_authorization(challenges: &AcmeChallenges, token: &str) -> Option<String> {
+    if let Some(key_authorization) = challenges
+        .read()
+        .ok()
+        .and_then(|challenges| challenges.get(token).cloned())
+    {
+        return Some(key_authorization);
+    }
+    let webroot = dotenv::var("ACME_WEBROOT").ok()?;
+    let path = Path::new(&webroot)
+        .join(ACME_CHALLENGE_PREFIX.trim_start_matches('/'))
+        .join(token);
+    fs::read_to_string(path)
+        .ok()
+        .map(|key_authorization| String::from(key_authorization.trim()))
+}
+
+// A complete response for a challenge request, None when it is not one or we don't have
+// the token and the request should go on as usual.
+pub fn challenge_reply(challenges: &AcmeChallenges, path: &str) -> Option<Vec<u8>> {
+    let key_authorization = key_authorization(challenges, challenge_token(path)?)?;
+    Some(
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n{}",
+            key_authorization.len(),
+            key_authorization
+        )
+        .into_bytes(),
+    )
+}
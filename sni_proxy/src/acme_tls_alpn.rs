@@ -0,0 +1,84 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use ring::digest;
+use rustls::{sign, ClientHello, ResolvesServerCert};
+
+// ACME TLS-ALPN-01 challenges (RFC 8737) are answered in the TLS handshake on the HTTPS
+// listener, for names where the CA can't reach us on port 80.
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+// Challenge certificates by name for the TLS-ALPN-01 challenges our ACME client has open,
+// its thread adds them and takes them away again.
+pub type TlsAlpnChallenges = Arc<RwLock<HashMap<String, sign::CertifiedKey>>>;
+
+// Wraps the resolver from the certificate plugin. A ClientHello offering acme-tls/1 is
+// the CA validating a name, it gets the challenge certificate for it and never a real
+// certificate, everything else goes to the plugin as before.
+pub struct AcmeTlsAlpnResolver {
+    inner: Arc<dyn ResolvesServerCert>,
+    challenges: TlsAlpnChallenges,
+}
+
+impl AcmeTlsAlpnResolver {
+    pub fn new(
+        inner: Arc<dyn ResolvesServerCert>,
+        challenges: TlsAlpnChallenges,
+    ) -> AcmeTlsAlpnResolver {
+        AcmeTlsAlpnResolver { inner, challenges }
+    }
+}
+
+impl ResolvesServerCert for AcmeTlsAlpnResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+        let is_challenge = client_hello
+            .alpn()
+            .map(|protocols| protocols.contains(&ACME_TLS_ALPN_PROTOCOL))
+            .unwrap_or(false);
+        if !is_challenge {
+            return self.inner.resolve(client_hello);
+        }
+        let name: &str = client_hello.server_name()?.into();
+        let certified_key = self
+            .challenges
+            .read()
+            .ok()?
+            .get(&name.to_lowercase())
+            .cloned();
+        if certified_key.is_none() {
+            debug!(target: "0","acme-tls/1 handshake for {} without an open challenge",name);
+        }
+        certified_key
+    }
+}
+
+// The self-signed certificate for name that proves we hold key_authorization, it has
+// only name as SAN and the SHA-256 of the key authorization in a critical acmeIdentifier
+// extension. The key is thrown away with the certificate.
+pub fn challenge_certificate(
+    name: &str,
+    key_authorization: &str,
+) -> Result<sign::CertifiedKey, String> {
+    let digest = digest::digest(&digest::SHA256, key_authorization.as_bytes());
+    let mut params = rcgen::CertificateParams::new(vec![String::from(name)]);
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(digest.as_ref())];
+    let (der, key) = rcgen::Certificate::from_params(params)
+        .and_then(|certificate| {
+            let der = certificate.serialize_der()?;
+            Ok((der, certificate.serialize_private_key_der()))
+        })
+        .map_err(|e| {
+            format!(
+                "Unable to create challenge certificate for {}: {:?}",
+                name, e
+            )
+        })?;
+    let key = sign::any_supported_type(&rustls::PrivateKey(key))
+        .map_err(|_| format!("Unable to use challenge key for {}", name))?;
+    Ok(sign::CertifiedKey::new(
+        vec![rustls::Certificate(der)],
+        Arc::new(key),
+    ))
+}
@@ -0,0 +1,119 @@
+use interfaces::{unix_now, CertificateExpiry};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::cert_expiry::{format_time, served_certificates, DAY};
+
+// What the admin listener shows, the main loop replaces it when it checks expiry.
+pub type SharedExpiry = Arc<Mutex<Vec<CertificateExpiry>>>;
+
+const MAX_ADMIN_REQUEST: usize = 8192;
+
+// Starts the admin listener on ADMIN (e.g. 127.0.0.1:9180) if it is set. It has its own
+// thread so a slow admin client never holds up the proxy. It serves
+//
+//   /certificates   Every name we have a certificate for and when it expires.
+//   /metrics        The same in Prometheus text format.
+//
+// There is no authentication, bind it to localhost or a management network.
+pub fn start_admin(expiry: SharedExpiry) -> io::Result<()> {
+    let admin_bind = dotenv::var("ADMIN").unwrap_or_default();
+    if admin_bind.is_empty() {
+        return Ok(());
+    }
+    info!(target: "0","Starting ADMIN bind({})",admin_bind);
+    let listener = TcpListener::bind(&admin_bind)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle_admin_request(stream, &expiry));
+            if let Err(e) = result {
+                debug!(target: "0","Admin request failed: {:?}",e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_admin_request(mut stream: TcpStream, expiry: &SharedExpiry) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_ADMIN_REQUEST {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let mut certificates = expiry.lock().map(|e| e.clone()).unwrap_or_default();
+    certificates.sort_by(|a, b| {
+        a.not_after
+            .cmp(&b.not_after)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    let (status, body) = match path {
+        "/certificates" => ("200 OK", certificate_listing(&certificates)),
+        "/metrics" => ("200 OK", certificate_metrics(&certificates)),
+        _ => ("404 Not Found", String::from("Not found\n")),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+fn certificate_listing(certificates: &[CertificateExpiry]) -> String {
+    let now = unix_now();
+    let mut listing = String::new();
+    for certificate in certificates {
+        let note = if certificate.not_before > now {
+            " (not valid yet)"
+        } else {
+            ""
+        };
+        listing.push_str(&format!(
+            "{:<50} {:>5} days  {} - {}{}\n",
+            certificate.name,
+            (certificate.not_after - now) / DAY,
+            format_time(certificate.not_before),
+            format_time(certificate.not_after),
+            note
+        ));
+    }
+    listing
+}
+
+// One series per host, for the certificate served_certificates picks.
+fn certificate_metrics(certificates: &[CertificateExpiry]) -> String {
+    let hosts = served_certificates(certificates);
+
+    let mut metrics = String::new();
+    metrics.push_str("# HELP sni_proxy_certificate_not_after_seconds notAfter of the longest lasting valid certificate for host.\n");
+    metrics.push_str("# TYPE sni_proxy_certificate_not_after_seconds gauge\n");
+    for certificate in &hosts {
+        metrics.push_str(&format!(
+            "sni_proxy_certificate_not_after_seconds{{host=\"{}\"}} {}\n",
+            certificate.name, certificate.not_after
+        ));
+    }
+    metrics.push_str("# HELP sni_proxy_certificate_not_before_seconds notBefore of the longest lasting valid certificate for host.\n");
+    metrics.push_str("# TYPE sni_proxy_certificate_not_before_seconds gauge\n");
+    for certificate in &hosts {
+        metrics.push_str(&format!(
+            "sni_proxy_certificate_not_before_seconds{{host=\"{}\"}} {}\n",
+            certificate.name, certificate.not_before
+        ));
+    }
+    metrics
+}
@@ -0,0 +1,98 @@
+use chrono::{TimeZone, Utc};
+use interfaces::{unix_now, CertificateExpiry};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// Days before notAfter we warn at, CERT_EXPIRY_WARN_DAYS overrides it.
+const DEFAULT_WARN_DAYS: &str = "30;14;3";
+// How often expiry is looked at, we also do it when the plugin has new certificates.
+pub const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub const DAY: i64 = 24 * 60 * 60;
+
+// Logs a warning once for every threshold a certificate passes, an error at the last one.
+pub struct ExpiryMonitor {
+    //Largest first.
+    warn_days: Vec<i64>,
+    //How many thresholds we have warned about for a (name, notAfter).
+    warned: HashMap<(String, i64), usize>,
+    last_check: Option<Instant>,
+}
+
+impl ExpiryMonitor {
+    pub fn new() -> ExpiryMonitor {
+        let warn_days = dotenv::var("CERT_EXPIRY_WARN_DAYS")
+            .unwrap_or_else(|_| String::from(DEFAULT_WARN_DAYS));
+        let mut warn_days: Vec<i64> = warn_days
+            .split(';')
+            .filter_map(|days| days.trim().parse().ok())
+            .collect();
+        warn_days.sort_unstable_by(|a, b| b.cmp(a));
+        warn_days.dedup();
+        ExpiryMonitor {
+            warn_days,
+            warned: HashMap::new(),
+            last_check: None,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_check
+            .map(|last_check| last_check.elapsed() >= EXPIRY_CHECK_INTERVAL)
+            .unwrap_or(true)
+    }
+
+    pub fn check(&mut self, expiry: &[CertificateExpiry]) {
+        self.last_check = Some(Instant::now());
+        let now = unix_now();
+        let mut warned = HashMap::new();
+        for certificate in served_certificates(expiry) {
+            let days_left = (certificate.not_after - now) / DAY;
+            let passed = self
+                .warn_days
+                .iter()
+                .filter(|days| days_left <= **days)
+                .count();
+            let key = (certificate.name.clone(), certificate.not_after);
+            if passed > self.warned.get(&key).copied().unwrap_or(0) {
+                if passed == self.warn_days.len() {
+                    error!(target: "0","Certificate for {} expires in {} days ({})",certificate.name,days_left,format_time(certificate.not_after));
+                } else {
+                    warn!(target: "0","Certificate for {} expires in {} days ({})",certificate.name,days_left,format_time(certificate.not_after));
+                }
+            }
+            warned.insert(key, passed);
+        }
+        //Forget renewed and removed certificates.
+        self.warned = warned;
+    }
+}
+
+// Per name the certificate that lasts longest of those valid now, what clients will get
+// once the others have expired. A renewal that is in place keeps the old one from being
+// warned about, one that is not valid yet does not hide that the current one expires.
+pub fn served_certificates(expiry: &[CertificateExpiry]) -> Vec<&CertificateExpiry> {
+    let now = unix_now();
+    let mut by_name: HashMap<&str, &CertificateExpiry> = HashMap::new();
+    for certificate in expiry {
+        let served = by_name.entry(&certificate.name).or_insert(certificate);
+        let valid = certificate.not_before <= now;
+        let served_valid = served.not_before <= now;
+        if (valid && !served_valid)
+            || (valid == served_valid && certificate.not_after > served.not_after)
+        {
+            *served = certificate;
+        }
+    }
+    let mut served: Vec<&CertificateExpiry> = by_name.values().copied().collect();
+    served.sort_by(|a, b| a.name.cmp(&b.name));
+    served
+}
+
+pub fn format_time(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| timestamp.to_string())
+}
@@ -0,0 +1,113 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use interfaces::{ClientAuthMode, ClientAuthPolicy, SniNames};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoServerSessionStorage,
+    RootCertStore, ServerConfig, ServerSession, Session,
+};
+
+use crate::routing::normalize_host;
+use crate::session_resumption::NoTickets;
+
+// ServerConfigs for the hosts that ask for a client certificate, by name. rustls asks
+// every client of a config for one or none of them, so a connection whose ClientHello
+// names such a host gets one of these instead of the shared config. Names with the same
+// policy share a config.
+#[derive(Default)]
+pub struct ClientAuthConfigs {
+    by_name: SniNames<ClientAuthConfig>,
+}
+
+impl fmt::Debug for ClientAuthConfigs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientAuthConfigs").finish()
+    }
+}
+
+// One ServerConfig asking for client certificates, and the policy it was made for.
+#[derive(Clone)]
+pub struct ClientAuthConfig(Arc<ServerConfig>);
+
+impl ClientAuthConfig {
+    pub fn server_config(&self) -> &Arc<ServerConfig> {
+        &self.0
+    }
+
+    // A client certificate checked for one host is good for the other.
+    pub fn same_policy(&self, other: &ClientAuthConfig) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for ClientAuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ClientAuthConfig").finish()
+    }
+}
+
+impl ClientAuthConfigs {
+    // base is the config everything else uses, only the client certificate verifier differs.
+    pub fn new(
+        base: &ServerConfig,
+        policies: &HashMap<String, ClientAuthPolicy>,
+    ) -> ClientAuthConfigs {
+        let mut configs: Vec<(&ClientAuthPolicy, ClientAuthConfig)> = Vec::new();
+        let mut by_name = SniNames::new();
+        for (name, policy) in policies {
+            if policy.mode == ClientAuthMode::None {
+                continue;
+            }
+            let config = match configs.iter().find(|(known, _)| *known == policy) {
+                Some((_, config)) => config.clone(),
+                None => {
+                    let config = ClientAuthConfig(Arc::new(client_auth_config(base, policy)));
+                    configs.push((policy, config.clone()));
+                    config
+                }
+            };
+            debug!(target: "0","Client certificate {:?} for {}",policy.mode,name);
+            if !by_name.insert(name, config) {
+                warn!(target: "0","Ignoring client auth for invalid name {}",name);
+            }
+        }
+        ClientAuthConfigs { by_name }
+    }
+
+    pub fn lookup(&self, host: &str) -> Option<&ClientAuthConfig> {
+        self.by_name.get(&normalize_host(host))
+    }
+}
+
+fn client_auth_config(base: &ServerConfig, policy: &ClientAuthPolicy) -> ServerConfig {
+    let mut roots = RootCertStore::empty();
+    for ca in &policy.ca_certificates {
+        if let Err(e) = roots.add(ca) {
+            warn!(target: "0","Ignoring client CA certificate: {:?}",e);
+        }
+    }
+    let mut config = base.clone();
+    config.set_client_certificate_verifier(match policy.mode {
+        ClientAuthMode::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+        _ => AllowAnyAuthenticatedClient::new(roots),
+    });
+    //A resumed session isn't asked for a certificate again, the policy may have changed
+    //since, or be another one on the proxy that made the ticket.
+    config.session_storage = Arc::new(NoServerSessionStorage {});
+    config.ticketer = Arc::new(NoTickets);
+    config
+}
+
+// The subject of the client certificate rustls verified, e.g. "CN=admin, O=Example".
+pub fn client_subject(session: &ServerSession) -> Option<String> {
+    let certificates = session.get_peer_certificates()?;
+    let (_, x509) = x509_parser::parse_x509_der(&certificates.first()?.0).ok()?;
+    //Nothing that could end the header early.
+    Some(
+        x509.tbs_certificate
+            .subject
+            .to_string()
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect(),
+    )
+}
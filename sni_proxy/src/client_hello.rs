@@ -0,0 +1,239 @@
+// Minimal parser for the first TLS record on a connection. We only need the SNI
+// host_name so we can decide if the connection should be terminated by rustls or
+// passed through untouched to the backend, before rustls has seen any bytes.
+// https://tools.ietf.org/html/rfc8446#section-4.1.2
+// https://tools.ietf.org/html/rfc6066#section-3
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0;
+
+// A TLS record can never be bigger than this, so peeking more than this is pointless.
+pub const MAX_CLIENT_HELLO_LEN: usize = RECORD_HEADER_LEN + 16384;
+
+#[derive(Debug, PartialEq)]
+pub enum ClientHelloPeek {
+    // We need more bytes from the client before we can say anything.
+    Incomplete,
+    // Not something we understand, let rustls handle it (and most likely fail).
+    // A ClientHello split over several records also ends up here.
+    NotClientHello,
+    // A complete ClientHello, with the SNI host_name if the client sent one.
+    ServerName(Option<String>),
+}
+
+// Looks at the start of a client stream and tries to find the SNI host_name.
+pub fn peek_server_name(buf: &[u8]) -> ClientHelloPeek {
+    if buf.len() < RECORD_HEADER_LEN {
+        return ClientHelloPeek::Incomplete;
+    }
+    if buf[0] != CONTENT_TYPE_HANDSHAKE {
+        return ClientHelloPeek::NotClientHello;
+    }
+    let record_len = ((buf[3] as usize) << 8) | buf[4] as usize;
+    if buf.len() < RECORD_HEADER_LEN + record_len {
+        return ClientHelloPeek::Incomplete;
+    }
+
+    let mut record = Reader::new(&buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len]);
+    match parse_client_hello(&mut record) {
+        Some(server_name) => ClientHelloPeek::ServerName(server_name),
+        None => ClientHelloPeek::NotClientHello,
+    }
+}
+
+// Walks the ClientHello up to the extensions and returns the first host_name
+// in the server_name extension. None means the message was malformed.
+fn parse_client_hello(record: &mut Reader) -> Option<Option<String>> {
+    if record.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let hello_len = record.u24()?;
+    let mut hello = Reader::new(record.take(hello_len)?);
+
+    //legacy_version and random
+    hello.take(2 + 32)?;
+    let session_id_len = hello.u8()? as usize;
+    hello.take(session_id_len)?;
+    let cipher_suites_len = hello.u16()? as usize;
+    hello.take(cipher_suites_len)?;
+    let compression_len = hello.u8()? as usize;
+    hello.take(compression_len)?;
+
+    //Old clients might not send any extensions at all
+    if hello.is_empty() {
+        return Some(None);
+    }
+
+    let extensions_len = hello.u16()? as usize;
+    let mut extensions = Reader::new(hello.take(extensions_len)?);
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let extension = extensions.take(extension_len)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut extension = Reader::new(extension);
+        let list_len = extension.u16()? as usize;
+        let mut list = Reader::new(extension.take(list_len)?);
+        while !list.is_empty() {
+            let name_type = list.u8()?;
+            let name_len = list.u16()? as usize;
+            let name = list.take(name_len)?;
+            if name_type == SERVER_NAME_TYPE_HOST_NAME {
+                return Some(Some(String::from_utf8_lossy(name).to_lowercase()));
+            }
+        }
+    }
+    Some(None)
+}
+
+// Cursor over a byte slice that never panics, every read returns None when
+// there is not enough data left.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() - self.pos < len {
+            return None;
+        }
+        let slice = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Some(slice)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| ((b[0] as u16) << 8) | b[1] as u16)
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A TLS record holding a ClientHello with the given extensions.
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[7; 32]);
+        //session id, one cipher suite and the null compression
+        hello.push(0);
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    fn server_name_extension(name: &str) -> Vec<u8> {
+        let mut list = vec![SERVER_NAME_TYPE_HOST_NAME];
+        list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        list.extend_from_slice(name.as_bytes());
+
+        let mut extension = EXTENSION_SERVER_NAME.to_be_bytes().to_vec();
+        extension.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+        extension.extend_from_slice(&(list.len() as u16).to_be_bytes());
+        extension.extend_from_slice(&list);
+        extension
+    }
+
+    #[test]
+    fn server_name_after_other_extensions() {
+        //supported_versions with TLS 1.3
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend_from_slice(&server_name_extension("WWW.Example.test"));
+        assert_eq!(
+            peek_server_name(&client_hello(&extensions)),
+            ClientHelloPeek::ServerName(Some("www.example.test".to_string()))
+        );
+    }
+
+    #[test]
+    fn server_name_absent() {
+        assert_eq!(
+            peek_server_name(&client_hello(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04])),
+            ClientHelloPeek::ServerName(None)
+        );
+        assert_eq!(
+            peek_server_name(&client_hello(&[])),
+            ClientHelloPeek::ServerName(None)
+        );
+    }
+
+    #[test]
+    fn truncated_client_hello_is_incomplete() {
+        let hello = client_hello(&server_name_extension("a.test"));
+        for len in 0..hello.len() {
+            assert_eq!(
+                peek_server_name(&hello[..len]),
+                ClientHelloPeek::Incomplete,
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn client_hello_over_several_records() {
+        let hello = client_hello(&server_name_extension("a.test"));
+        let handshake = &hello[RECORD_HEADER_LEN..];
+        let (first, second) = handshake.split_at(20);
+        let mut fragmented = Vec::new();
+        for fragment in &[first, second] {
+            fragmented.extend_from_slice(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+            fragmented.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            fragmented.extend_from_slice(fragment);
+        }
+        assert_eq!(
+            peek_server_name(&fragmented),
+            ClientHelloPeek::NotClientHello
+        );
+    }
+
+    #[test]
+    fn malformed_client_hello() {
+        //Not a handshake record
+        assert_eq!(
+            peek_server_name(b"GET / HTTP/1.1\r\n"),
+            ClientHelloPeek::NotClientHello
+        );
+        //The server_name extension claims more bytes than the record has
+        let mut extension = server_name_extension("a.test");
+        extension[3] += 10;
+        assert_eq!(
+            peek_server_name(&client_hello(&extension)),
+            ClientHelloPeek::NotClientHello
+        );
+    }
+}
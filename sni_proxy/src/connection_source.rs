@@ -8,21 +8,59 @@ use rustls;
 use rustls::{Session, TLSError};
 
 //use cmp::min;
-use httparse::{self};
 use std::{
     cmp::min,
     //    cmp,
-    collections::{HashMap, VecDeque},
+    collections::VecDeque,
     io,
     io::{Read, Write},
     net,
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
+use crate::{
+    acme_challenge::{challenge_reply, AcmeChallenges},
+    client_auth::{client_subject, ClientAuthConfig, ClientAuthConfigs},
+    client_hello::{peek_server_name, ClientHelloPeek, MAX_CLIENT_HELLO_LEN},
+    forward_target::ForwardTarget,
+    http_parser::{
+        add_forwarded_headers, is_trusted_proxy, parse_request_head, parse_response_head,
+        BodyFramer, BodyLength, RequestHead,
+    },
+    plugin_loader::SharedCacher,
+    proxy_protocol::{peek_proxy_header, proxy_header, ProxyHeaderPeek, MAX_PROXY_HEADER_LEN},
+    routing::RoutingTable,
+    upstream_tls::UpstreamTlsConfigs,
+};
 use crate::{ok_macro, process_error_handling, read_error_handling, write_error_handling};
 
-use interfaces::Cacher;
+//How long a keep-alive connection may sit idle after a response, IDLE_TIMEOUT_SECONDS
+//overrides it and 0 turns it off.
+const DEFAULT_IDLE_TIMEOUT_SECONDS: u64 = 3;
+//Responses bigger than this are passed along but not given to the cacher.
+const MAX_CACHED_RESPONSE: usize = 8 * 1024 * 1024;
+//For a Host whose client certificate policy is not the one the handshake was done with.
+const MISDIRECTED_REPLY: &[u8] =
+    b"HTTP/1.1 421 Misdirected Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+//For a request head we can't read, or whose body length is ambiguous.
+const BAD_REQUEST_REPLY: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+//For requests whose backend we can't connect to.
+const BAD_GATEWAY_REPLY: &[u8] =
+    b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+//For a host that asks for client certificates, requested without TLS.
+const FORBIDDEN_REPLY: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// A request sent to the backend that we are waiting for the response to.
+#[derive(Debug)]
+struct PendingRequest {
+    method: String,
+    host: String,
+    path: String,
+    close: bool,
+}
 
 #[derive(Debug)] //Instant::now();
 pub struct ConnectionSource {
@@ -32,12 +70,34 @@ pub struct ConnectionSource {
     pub server_token: Token,
     pub forward_token: Token,
     pub forward_host: String,
-    pub forward_lookup: Arc<HashMap<String, String>>,
+    pub forward_lookup: Arc<RoutingTable>,
+    //The cache plugin loaded at startup, None when running without cache.
+    cacher: Option<SharedCacher>,
+    //HTTP-01 challenges our ACME client has open.
+    acme_challenges: AcmeChallenges,
+    //Configs for the hosts that ask for client certificates, and the one this
+    //connection got from its SNI host if it is one of them.
+    client_auth: Arc<ClientAuthConfigs>,
+    client_auth_config: Option<ClientAuthConfig>,
+    //ClientConfigs for https:// forwards.
+    upstream_tls: Arc<UpstreamTlsConfigs>,
     //TODO: Remove do_tls and use tls_session.is_some instead.
     pub do_tls: bool,
+    //Set when the SNI host is a passthrough forward, then we never terminate TLS
+    //and just splice bytes between client and backend.
+    pub passthrough: bool,
+    client_hello_checked: bool,
+    //Set when we are behind a load balancer that sends a PROXY header first,
+    //client_addr is then the real client instead of the balancer.
+    expect_proxy_header: bool,
+    client_addr: Option<net::SocketAddr>,
     request_host: String,
 
     forward_stream: Option<TcpStream>,
+    //Set when the forward is https://, everything to and from the backend goes through it.
+    forward_tls: Option<rustls::ClientSession>,
+    //The PROXY header for an https:// backend, it goes in the clear before the handshake.
+    forward_preamble: Vec<u8>,
     send_to_farward: VecDeque<Vec<u8>>,
     buf_forward: Vec<u8>,
     send_to_client: VecDeque<Vec<u8>>,
@@ -49,7 +109,26 @@ pub struct ConnectionSource {
     bytes_sent: usize,
     bytes_received: usize,
     activity_timeout: Option<Instant>,
+    idle_timeout: Option<Duration>,
     http_get_path: String,
+    //Where we are in the body of the request being sent to the backend, None
+    //when the next thing from the client is a request head.
+    request_body: Option<BodyFramer>,
+    //The current request was answered by us (redirect or cache), not the backend.
+    discard_request_body: bool,
+    //Requests sent to the backend in order, the front one is being answered.
+    pending_requests: VecDeque<PendingRequest>,
+    //Where we are in the body of the response being sent to the client, None
+    //when the next thing from the backend is a response head.
+    response_body: Option<BodyFramer>,
+    //The whole current response, for the cacher.
+    response_cache: Option<Vec<u8>>,
+    response_close: bool,
+    forward_eof: bool,
+    //The connect to the backend has gone through, until then it may still be refused.
+    forward_connected: bool,
+    //Close the client connection when everything queued for it is sent.
+    close_when_sent: bool,
 }
 
 // All functions here are needed to comply with the source implementation
@@ -123,8 +202,8 @@ impl Source for ConnectionSource {
         let mut server_addr = String::new();
         //        let mut sni_host="";
         let mut server_port = 0;
-        if self.server_stream.peer_addr().is_ok() {
-            server_addr = self.server_stream.peer_addr().unwrap().to_string()
+        if let Ok(peer_addr) = self.peer_addr() {
+            server_addr = peer_addr.to_string()
         }
         // if self.tls_session.is_some() && self.tls_session.as_mut().unwrap().get_sni_hostname().is_some() {
         //     sni_host = self.tls_session.as_mut().unwrap().get_sni_hostname().unwrap();
@@ -149,12 +228,18 @@ impl Source for ConnectionSource {
 
 // We have a new function so that we can have private variables.
 impl ConnectionSource {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         connection: TcpStream,
         server_token: Token,
         forward_token: Token,
         tls_session: Option<rustls::ServerSession>,
-        forward_lookup: Arc<HashMap<String, String>>,
+        forward_lookup: Arc<RoutingTable>,
+        cacher: Option<SharedCacher>,
+        acme_challenges: AcmeChallenges,
+        client_auth: Arc<ClientAuthConfigs>,
+        upstream_tls: Arc<UpstreamTlsConfigs>,
+        expect_proxy_header: bool,
     ) -> ConnectionSource {
         let m_session: ConnectionSource = ConnectionSource {
             server_stream: connection,
@@ -162,12 +247,23 @@ impl ConnectionSource {
             forward_stream: None,
             forward_token: forward_token,
             forward_lookup: forward_lookup,
+            cacher,
+            acme_challenges,
+            client_auth,
+            client_auth_config: None,
+            upstream_tls,
+            forward_tls: None,
+            forward_preamble: Vec::new(),
             send_to_farward: VecDeque::new(),
             buf_forward: Vec::new(),
             send_to_client: VecDeque::new(),
             buf_client: Vec::new(),
             do_tls: tls_session.is_some(),
             tls_session: tls_session,
+            passthrough: false,
+            client_hello_checked: false,
+            expect_proxy_header,
+            client_addr: None,
             request_host: String::new(),
             closing: false,
             done_closing: false,
@@ -176,14 +272,50 @@ impl ConnectionSource {
             bytes_sent: 0,
             bytes_received: 0,
             activity_timeout: None,
+            idle_timeout: idle_timeout(),
             http_get_path: String::new(),
+            request_body: None,
+            discard_request_body: false,
+            pending_requests: VecDeque::new(),
+            response_body: None,
+            response_cache: None,
+            response_close: false,
+            forward_eof: false,
+            forward_connected: false,
+            close_when_sent: false,
         };
         m_session
     }
 }
 
+fn idle_timeout() -> Option<Duration> {
+    let seconds = dotenv::var("IDLE_TIMEOUT_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.trim().parse().ok())
+        .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECONDS);
+    Some(Duration::from_secs(seconds)).filter(|timeout| *timeout > Duration::from_secs(0))
+}
+
 // HTTP
 impl ConnectionSource {
+    // The timer runs from the last write to the client, but only counts once nothing is
+    // in flight: a backend that pauses in the middle of a response is not idle. Raw
+    // streams (passthrough and upgraded connections) have no requests to go by and are
+    // never timed out here.
+    fn is_idle_too_long(&self) -> bool {
+        let (timeout, since) = match (self.idle_timeout, self.activity_timeout) {
+            (Some(timeout), Some(since)) => (timeout, since),
+            _ => return false,
+        };
+        !self.passthrough
+            && self.pending_requests.is_empty()
+            && self.request_body.is_none()
+            && self.response_body.is_none()
+            && self.send_to_farward.is_empty()
+            && self.send_to_client.is_empty()
+            && since.elapsed() > timeout
+    }
+
     fn https_reader(&mut self) -> bool {
         trace!("Entering HTTPS_READER");
         loop {
@@ -229,20 +361,14 @@ impl ConnectionSource {
     }
 
     fn https_writer(&mut self) -> Option<bool> {
-        while self.send_to_client.len() > 0 {
-            let buf = self.send_to_client.pop_front();
-            let len = &buf.clone().unwrap().len();
-            let res = self
-                .tls_session
-                .as_mut()
-                .unwrap()
-                .write_all(&buf.unwrap().as_mut_slice());
+        while let Some(buf) = self.send_to_client.pop_front() {
+            //rustls buffers everything we give it, so there is no partial write here.
+            let res = self.tls_session.as_mut().unwrap().write_all(&buf);
             match res {
                 Ok(()) => {
                     //self.activity_timeout = Instant::now();
-                    self.bytes_received = self.bytes_received + len;
+                    self.bytes_received += buf.len();
                     self.activity_timeout = Some(Instant::now());
-                    return Some(true);
                 }
                 Err(e) => {
                     error!(target: &self.server_token.0.to_string(),"https_writer Unknown error: \r\n{:?}",e);
@@ -297,24 +423,29 @@ impl ConnectionSource {
     }
 
     fn http_writer(&mut self) -> Option<bool> {
-        while self.send_to_client.len() > 0 {
-            let buf = self.send_to_client.pop_front();
-            let len = buf.clone().unwrap().len();
+        while let Some(buf) = self.send_to_client.pop_front() {
             //            error!(
             //                "Sending response http:\r\n{}",
             //                String::from_utf8_lossy(&buf.clone().unwrap()[0..min(512, len)])
             //            );
-            let res = self.server_stream.write_all(&buf.unwrap().as_mut_slice());
-            match res {
-                Ok(()) => {
-                    self.bytes_received = self.bytes_received + len;
+            match self.server_stream.write(&buf) {
+                Ok(n) => {
+                    self.bytes_received += n;
                     self.activity_timeout = Some(Instant::now());
-                    return Some(true);
+                    //The socket is full, the rest waits for the next writable event.
+                    if n < buf.len() {
+                        self.send_to_client.push_front(buf[n..].to_vec());
+                        return Some(false);
+                    }
                 }
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.send_to_client.push_front(buf);
                     self.activity_timeout = Some(Instant::now());
                     return Some(false);
                 }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    self.send_to_client.push_front(buf);
+                }
                 Err(e) => {
                     error!(target: &self.server_token.0.to_string(),"http_writer Unknown error: \r\n{:?}",e);
                     return Some(false);
@@ -325,6 +456,9 @@ impl ConnectionSource {
     }
 
     fn http_fwd_reader(&mut self) -> bool {
+        if self.forward_tls.is_some() {
+            return self.https_fwd_reader();
+        }
         loop {
             let mut buf = [0; 1024];
             match self.forward_stream.as_mut().unwrap().read(&mut buf) {
@@ -333,16 +467,15 @@ impl ConnectionSource {
                 //     trace!(target: &self.server_token.0.to_string(),"http_fwd_reader read 0");
                 //     return true;
                 // }
+                Ok(0) => {
+                    trace!(target: &self.server_token.0.to_string(),"http_fwd_reader EOF");
+                    self.forward_eof = true;
+                    return true;
+                }
                 Ok(n) => {
-                    if n > 0 {
-                        self.buf_client.extend_from_slice(&buf[0..n]);
-                    }
+                    self.buf_client.extend_from_slice(&buf[0..n]);
                     trace!(target: &self.server_token.0.to_string(),"http_fwd_reader read {}",n);
-                    if n < 1024 {
-                        self.print_header(&self.buf_client);
-                        //self.activity_timeout= Instant::now();
-                        return true;
-                    }
+                    //Keep reading until WouldBlock, we only get one event per batch of data.
                 }
                 //Looks to be what registers when finished reading.
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -366,25 +499,132 @@ impl ConnectionSource {
     }
 
     fn http_fwd_writer(&mut self) -> bool {
-        while self.send_to_farward.len() > 0 {
-            let buf = self.send_to_farward.pop_front();
-            trace!(target: &self.server_token.0.to_string(),"http_fwd_writer data: \r\n{}",String::from_utf8_lossy(&buf.clone().unwrap().as_slice()));
-            let res = self
-                .forward_stream
-                .as_mut()
-                .unwrap()
-                .write_all(&buf.unwrap().as_mut_slice());
-            match res {
-                Ok(()) => {
+        if self.forward_tls.is_some() {
+            return self.https_fwd_writer();
+        }
+        while let Some(buf) = self.send_to_farward.pop_front() {
+            trace!(target: &self.server_token.0.to_string(),"http_fwd_writer data: \r\n{}",String::from_utf8_lossy(&buf[0..min(256, buf.len())]));
+            match self.forward_stream.as_mut().unwrap().write(&buf) {
+                Ok(n) => {
+                    //The socket is full, the rest waits for the next writable event.
+                    if n < buf.len() {
+                        self.send_to_farward.push_front(buf[n..].to_vec());
+                        return true;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.send_to_farward.push_front(buf);
                     return true;
                 }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    self.send_to_farward.push_front(buf);
+                }
                 Err(e) => {
                     trace!(target: &self.server_token.0.to_string(),"http_fwd_writer Unknown error: \r\n{:?}",e);
                     return false;
                 }
             }
         }
-        return true;
+        true
+    }
+
+    // Reads TLS records from an https:// backend, what they decrypt to goes in buf_client
+    // like in http_fwd_reader.
+    fn https_fwd_reader(&mut self) -> bool {
+        let tls = self.forward_tls.as_mut().unwrap();
+        let forward_stream = self.forward_stream.as_mut().unwrap();
+        loop {
+            match tls.read_tls(forward_stream) {
+                Ok(0) => {
+                    trace!(target: &self.server_token.0.to_string(),"https_fwd_reader EOF");
+                    self.forward_eof = true;
+                    break;
+                }
+                Ok(n) => {
+                    trace!(target: &self.server_token.0.to_string(),"https_fwd_reader read {}",n);
+                    if let Err(e) = tls.process_new_packets() {
+                        error!(target: &self.server_token.0.to_string(),"TLS error from backend {}: {:?}",self.forward_host,e);
+                        return false;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!(target: &self.server_token.0.to_string(),"https_fwd_reader Unknown error: \r\n{:?}",e);
+                    return false;
+                }
+            }
+        }
+        let mut buf = [0; 4096];
+        loop {
+            match tls.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.buf_client.extend_from_slice(&buf[0..n]),
+                //The backend sent close_notify.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {
+                    self.forward_eof = true;
+                    break;
+                }
+                Err(e) => {
+                    error!(target: &self.server_token.0.to_string(),"https_fwd_reader Unknown error: \r\n{:?}",e);
+                    return false;
+                }
+            }
+        }
+        if !self.buf_client.is_empty() {
+            self.print_header(&self.buf_client);
+        }
+        !self.buf_client.is_empty() || self.forward_eof
+    }
+
+    // Gives everything queued for an https:// backend to its ClientSession, which holds
+    // it until the handshake is done, and writes as much TLS as the socket takes.
+    fn https_fwd_writer(&mut self) -> bool {
+        let tls = self.forward_tls.as_mut().unwrap();
+        let forward_stream = self.forward_stream.as_mut().unwrap();
+        while !self.forward_preamble.is_empty() {
+            match forward_stream.write(&self.forward_preamble) {
+                Ok(n) => {
+                    self.forward_preamble.drain(0..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    trace!(target: &self.server_token.0.to_string(),"https_fwd_writer Unknown error: \r\n{:?}",e);
+                    return false;
+                }
+            }
+        }
+        while let Some(buf) = self.send_to_farward.pop_front() {
+            trace!(target: &self.server_token.0.to_string(),"https_fwd_writer data: \r\n{}",String::from_utf8_lossy(&buf[0..min(256, buf.len())]));
+            if let Err(e) = tls.write_all(&buf) {
+                error!(target: &self.server_token.0.to_string(),"https_fwd_writer Unknown error: \r\n{:?}",e);
+                return false;
+            }
+        }
+        while tls.wants_write() {
+            match tls.write_tls(forward_stream) {
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    trace!(target: &self.server_token.0.to_string(),"https_fwd_writer Unknown error: \r\n{:?}",e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    // Something for the backend, a request or with https:// TLS records of the handshake.
+    fn forward_wants_write(&self) -> bool {
+        !self.send_to_farward.is_empty()
+            || !self.forward_preamble.is_empty()
+            || self
+                .forward_tls
+                .as_ref()
+                .map(|tls| tls.wants_write())
+                .unwrap_or(false)
     }
 
     fn print_header(&self, buf: &Vec<u8>) {
@@ -396,49 +636,442 @@ impl ConnectionSource {
         }
     }
 
-    fn set_forward_adress(&mut self) -> bool {
-        if !self.forward_host.is_empty() {
-            return true;
+    // The client address, from the PROXY header if we got one, else the socket peer.
+    pub fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        match self.client_addr {
+            Some(client_addr) => Ok(client_addr),
+            None => self.server_stream.peer_addr(),
         }
-        if String::from_utf8_lossy(&self.buf_forward.as_slice()).contains("\r\n\r\n") {
-            //trace!("{}", String::from_utf8_lossy(&self.buf_forward.as_slice()));
-            //TODO should be a reusable struct maby.
-            self.http_parse_set_host();
+    }
 
-            return true;
+    // Reads the PROXY header a load balancer sends before any client data. We peek
+    // first so that we only consume the header and nothing of the TLS or HTTP data.
+    // Returns false if the header is not complete yet and we need more data.
+    fn read_proxy_header(&mut self) -> bool {
+        let mut buf = vec![0; MAX_PROXY_HEADER_LEN];
+        let n = match self.server_stream.peek(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return false;
+            }
+            Err(e) => {
+                error!(target: &self.server_token.0.to_string(),"read_proxy_header peek error: \r\n{:?}",e);
+                self.closing = true;
+                return true;
+            }
+        };
+
+        match peek_proxy_header(&buf[0..n]) {
+            ProxyHeaderPeek::Incomplete if n > 0 => {
+                trace!(target: &self.server_token.0.to_string(),"read_proxy_header incomplete ({} bytes)",n);
+                false
+            }
+            ProxyHeaderPeek::Header { header_len, source } => {
+                let mut header = vec![0; header_len];
+                if let Err(e) = self.server_stream.read_exact(&mut header) {
+                    error!(target: &self.server_token.0.to_string(),"read_proxy_header read error: \r\n{:?}",e);
+                    self.closing = true;
+                }
+                debug!(target: &self.server_token.0.to_string(),"PROXY header from {:?} client {:?}",self.server_stream.peer_addr(),source);
+                self.client_addr = source;
+                self.expect_proxy_header = false;
+                true
+            }
+            _ => {
+                error!(target: &self.server_token.0.to_string(),"Expected a PROXY header from {:?}, closing",self.server_stream.peer_addr());
+                self.expect_proxy_header = false;
+                self.closing = true;
+                true
+            }
         }
-        true
     }
 
-    fn http_parse_set_host(&mut self) -> bool {
-        let mut headers = [httparse::EMPTY_HEADER; 200];
-        let mut req = httparse::Request::new(&mut headers);
-        let b: Vec<u8> = Vec::from(self.buf_forward.clone());
-        match req.parse(b.as_slice()) {
-            Ok(_) => (),
+    // Peeks at the ClientHello without consuming it, so rustls still gets every byte
+    // if we terminate TLS. If the SNI host asks for client certificates the tls_session
+    // is started again with its config. If it has a passthrough forward we drop the
+    // tls_session and handle the connection as raw bytes from here on.
+    // Returns false if the ClientHello is not complete yet and we need more data.
+    fn check_client_hello(&mut self) -> bool {
+        let mut buf = vec![0; MAX_CLIENT_HELLO_LEN];
+        let n = match self.server_stream.peek(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return false;
+            }
             Err(e) => {
-                error!(target: &self.server_token.0.to_string(),"Read http-parse error unknown: {:?}",e);
+                error!(target: &self.server_token.0.to_string(),"check_client_hello peek error: \r\n{:?}",e);
+                self.closing = true;
+                return true;
+            }
+        };
+
+        let server_name = match peek_server_name(&buf[0..n]) {
+            ClientHelloPeek::Incomplete if n > 0 && n < buf.len() => {
+                trace!(target: &self.server_token.0.to_string(),"check_client_hello incomplete ClientHello ({} bytes)",n);
                 return false;
             }
+            ClientHelloPeek::ServerName(server_name) => server_name,
+            _ => None,
         };
+        self.client_hello_checked = true;
+
+        if let Some(server_name) = server_name {
+            if let Some(config) = self.client_auth.lookup(&server_name) {
+                debug!(target: &self.server_token.0.to_string(),"Asking {} for a client certificate",server_name);
+                self.tls_session = Some(rustls::ServerSession::new(config.server_config()));
+                self.client_auth_config = Some(config.clone());
+            }
+            let target = match self.forward_lookup.lookup(&server_name) {
+                Some(target) => ForwardTarget::parse(target),
+                None => return true,
+            };
+            if target.is_passthrough() {
+                debug!(target: &self.server_token.0.to_string(),"TLS passthrough for {} => {}",server_name,target.address);
+                self.request_host = server_name;
+                self.tls_session = None;
+                self.do_tls = false;
+                self.passthrough = true;
+            }
+        }
+        true
+    }
+
+    // Tells the backend who the client is, head has to be a complete request head.
+    // None if we can't, the request must not go out with the client's own headers.
+    fn forwarded_headers(&self, head: Vec<u8>) -> Option<Vec<u8>> {
+        let client = match self.peer_addr() {
+            Ok(client) => client,
+            Err(e) => {
+                error!(target: &self.server_token.0.to_string(),"No client adress for forwarded headers {:?}",e);
+                return None;
+            }
+        };
+        let proto = if self.do_tls { "https" } else { "http" };
+        let subject = self.tls_session.as_ref().and_then(client_subject);
+        add_forwarded_headers(
+            &head,
+            client,
+            proto,
+            is_trusted_proxy(&client.ip()),
+            subject.as_deref(),
+        )
+    }
+
+    // A client certificate was only checked against the policy of the SNI host, other
+    // hosts on the connection must have that same policy or none.
+    fn client_auth_allows(&self, host: &str) -> bool {
+        match (self.client_auth.lookup(host), self.client_auth_config.as_ref()) {
+            (None, _) => true,
+            (Some(wanted), Some(checked)) => wanted.same_policy(checked),
+            (Some(_), None) => false,
+        }
+    }
+
+    fn lookup_forward(&self, host: &str) -> String {
+        self.forward_lookup
+            .lookup(host)
+            .cloned()
+            .unwrap_or_else(|| dotenv::var("DEFAULT_FORWARD").unwrap_or_default())
+    }
+
+    // The answer and whether to close after it for requests that never go to the backend.
+    fn local_reply(&self, head: &RequestHead) -> Option<(Vec<u8>, bool)> {
+        //Without a Host there is nothing to route on.
+        if head.host.is_empty() {
+            debug!(target: &self.server_token.0.to_string(),"400 for {} {}, it has no Host",head.method,head.path);
+            return Some((BAD_REQUEST_REPLY.to_vec(), true));
+        }
+        if self.do_tls && !self.client_auth_allows(&head.host) {
+            debug!(target: &self.server_token.0.to_string(),"421 for {}, its client certificate policy is not the one of the SNI host",head.host);
+            return Some((MISDIRECTED_REPLY.to_vec(), true));
+        }
+        //Challenges go before the redirect, ACME validates over plain HTTP.
+        if let (false, Some(reply)) = (self.do_tls, challenge_reply(&self.acme_challenges, &head.path)) {
+            debug!(target: &self.server_token.0.to_string(),"ACME challenge for {} {}",head.host,head.path);
+            return Some((reply, head.close));
+        }
+        if let Some(reply) = self.redirect_reply(&head.host, &head.path) {
+            return Some((reply, head.close));
+        }
+        //Without TLS there is no client certificate, such hosts are only served on https.
+        if !self.do_tls && self.client_auth.lookup(&head.host).is_some() {
+            debug!(target: &self.server_token.0.to_string(),"403 for {} over plain HTTP, it asks for client certificates",head.host);
+            return Some((FORBIDDEN_REPLY.to_vec(), true));
+        }
+        //A passthrough backend only takes the TLS connection itself, routed on the SNI.
+        //Over plain HTTP it can't be reached at all, on a TLS connection we terminated
+        //the client has to connect again with this host in SNI.
+        if ForwardTarget::parse(&self.lookup_forward(&head.host)).is_passthrough() {
+            debug!(target: &self.server_token.0.to_string(),"{} has a passthrough forward, not for HTTP requests",head.host);
+            let reply = if self.do_tls { MISDIRECTED_REPLY } else { BAD_GATEWAY_REPLY };
+            return Some((reply.to_vec(), true));
+        }
+        if let (true, Some(cacher)) = (self.do_tls, self.cacher.as_ref()) {
+            let cache = *cacher.borrow().cacher().cache_read_path(&head.host, &head.path);
+            if let Some(cache) = cache {
+                return Some((cache, head.close));
+            }
+        }
+        None
+    }
+
+    // Goes through what the client has sent one HTTP/1.1 message at a time. Every request
+    // head is routed on its own Host header, so a keep-alive connection used for several
+    // hosts gets each request to the right backend. Bodies are sent along as they arrive,
+    // Content-Length or chunked encoding tells us where the next request starts.
+    fn route_requests(&mut self, registry: &Registry) {
+        while !self.buf_forward.is_empty() && !self.closing {
+            if self.passthrough {
+                //Raw bytes (TLS passthrough or an upgraded connection), just send it along.
+                let data: Vec<u8> = self.buf_forward.drain(..).collect();
+                self.send_to_farward.push_back(data);
+                break;
+            }
+
+            if let Some(body) = self.request_body.as_mut() {
+                let n = match body.advance(&self.buf_forward) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        error!(target: &self.server_token.0.to_string(),"Request body framing error: {}",e);
+                        self.closing = true;
+                        return;
+                    }
+                };
+                if body.is_done() {
+                    self.request_body = None;
+                }
+                let chunk: Vec<u8> = self.buf_forward.drain(0..n).collect();
+                if !self.discard_request_body && !chunk.is_empty() {
+                    self.send_to_farward.push_back(chunk);
+                }
+                continue;
+            }
+
+            //Nothing after a request that closes the connection is answered.
+            if self.close_when_sent {
+                self.buf_forward.clear();
+                break;
+            }
+            let head = match parse_request_head(&self.buf_forward) {
+                Ok(Some(head)) => head,
+                Ok(None) => break,
+                Err(e) => {
+                    //A 400 now would overtake the responses still to come, it waits in
+                    //buf_forward until they are sent.
+                    if self.pending_requests.is_empty() {
+                        error!(target: &self.server_token.0.to_string(),"Read http-parse error: {}",e);
+                        self.send_to_client.push_back(BAD_REQUEST_REPLY.to_vec());
+                        self.close_when_sent = true;
+                        self.buf_forward.clear();
+                    }
+                    break;
+                }
+            };
+
+            //Requests we answer ourselves are answered in turn too.
+            if let Some((reply, close)) = self.local_reply(&head) {
+                if !self.pending_requests.is_empty() {
+                    trace!(target: &self.server_token.0.to_string(),"Waiting for {} responses before answering {}{}",self.pending_requests.len(),head.host,head.path);
+                    break;
+                }
+                self.buf_forward.drain(0..head.head_len);
+                self.request_body = Some(BodyFramer::new(head.body));
+                self.discard_request_body = true;
+                self.send_to_client.push_back(reply);
+                self.close_when_sent |= close;
+                continue;
+            }
+
+            //A new host on this connection might live on another backend. We can only
+            //switch when everything for the old backend has been sent, until then the
+            //request waits in buf_forward.
+            if head.host != self.request_host
+                && self.forward_stream.is_some()
+                && self.lookup_forward(&head.host) != self.forward_host
+            {
+                if !self.send_to_farward.is_empty() || !self.pending_requests.is_empty() {
+                    trace!(target: &self.server_token.0.to_string(),"Waiting for {} to finish before switching to {}",self.forward_host,head.host);
+                    break;
+                }
+                debug!(target: &self.server_token.0.to_string(),"Switching backend {} => {} for {}",self.request_host,self.forward_host,head.host);
+                self.close_forward_stream(registry);
+            }
+
+            self.request_host = head.host;
+            self.http_get_path = head.path;
+            trace!(target: &self.server_token.0.to_string(),"Method: {}, Path: {}, Host: {}",
+                head.method,self.http_get_path,self.request_host);
+
+            let head_bytes: Vec<u8> = self.buf_forward.drain(0..head.head_len).collect();
+            self.request_body = Some(BodyFramer::new(head.body));
+
+            let head_bytes = match self.forwarded_headers(head_bytes) {
+                Some(head_bytes) => head_bytes,
+                None => {
+                    self.closing = true;
+                    return;
+                }
+            };
+            self.discard_request_body = false;
+            self.pending_requests.push_back(PendingRequest {
+                method: head.method,
+                host: self.request_host.clone(),
+                path: self.http_get_path.clone(),
+                close: head.close,
+            });
+            self.send_to_farward.push_back(head_bytes);
+        }
+
+        if !self.send_to_farward.is_empty() {
+            self.activate_forward_stream(registry);
+        }
+    }
+
+    // Goes through what the backend has sent one response at a time, so we know when a
+    // response is complete. That is when the cacher gets to see it, and when it is safe
+    // to switch backend or close a connection that asked for it.
+    fn frame_responses(&mut self, registry: &Registry) {
+        while !self.buf_client.is_empty() && !self.closing {
+            if self.passthrough {
+                let data: Vec<u8> = self.buf_client.drain(..).collect();
+                self.send_to_client.push_back(data);
+                break;
+            }
+
+            if let Some(body) = self.response_body.as_mut() {
+                let n = match body.advance(&self.buf_client) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        error!(target: &self.server_token.0.to_string(),"Response body framing error: {}",e);
+                        self.closing = true;
+                        return;
+                    }
+                };
+                let done = body.is_done();
+                let chunk: Vec<u8> = self.buf_client.drain(0..n).collect();
+                self.add_to_response_cache(&chunk);
+                self.send_to_client.push_back(chunk);
+                if done {
+                    self.finish_response(registry);
+                }
+                continue;
+            }
 
-        //TODO is host always on index 0?
-        self.request_host = format!("{}", String::from_utf8_lossy(req.headers[0].value));
+            let method = match self.pending_requests.front() {
+                Some(request) => request.method.clone(),
+                None => String::new(),
+            };
+            let head = match parse_response_head(&self.buf_client, &method) {
+                Ok(Some(head)) => head,
+                Ok(None) => break,
+                Err(e) => {
+                    error!(target: &self.server_token.0.to_string(),"Read http-parse error in response: {}",e);
+                    self.closing = true;
+                    return;
+                }
+            };
+            let head_bytes: Vec<u8> = self.buf_client.drain(0..head.head_len).collect();
+            trace!(target: &self.server_token.0.to_string(),"Response {} for {} {:?}",head.status,method,head.body);
+            self.send_to_client.push_back(head_bytes.clone());
+
+            if head.status == 101 {
+                //Switching protocols (websockets), from now on it is just bytes both ways.
+                debug!(target: &self.server_token.0.to_string(),"Upgraded connection for {}",self.request_host);
+                self.passthrough = true;
+                self.request_body = None;
+                self.pending_requests.clear();
+                continue;
+            }
+            if head.status < 200 {
+                //Informational like 100 Continue, the real response comes after.
+                continue;
+            }
 
-        self.http_get_path = String::from(req.path.unwrap_or_else(|| ""));
+            self.response_close = head.close;
+            //No point in collecting the response if there is no cacher to give it to.
+            self.response_cache = self.cacher.as_ref().map(|_| head_bytes);
+            self.response_body = Some(BodyFramer::new(head.body));
+            if head.body == BodyLength::Empty {
+                self.finish_response(registry);
+            }
+        }
+    }
 
-        trace!(target: &self.server_token.0.to_string(),"Method: {}, Path: {}, Host: {}",
-            req.method.unwrap_or(""),
-            req.path.unwrap_or(""),self.request_host);
-        for h in req.headers {
-            trace!(target: &self.server_token.0.to_string(),"Header --> {}: {}",h.name,String::from_utf8_lossy(h.value));
+    fn add_to_response_cache(&mut self, chunk: &[u8]) {
+        if let Some(response) = self.response_cache.as_mut() {
+            if response.len() + chunk.len() > MAX_CACHED_RESPONSE {
+                self.response_cache = None;
+            } else {
+                response.extend_from_slice(chunk);
+            }
         }
-        return true;
+    }
+
+    fn finish_response(&mut self, registry: &Registry) {
+        self.response_body = None;
+        let request = self.pending_requests.pop_front();
+        if let (Some(request), Some(response), Some(cacher)) = (
+            request.as_ref(),
+            self.response_cache.take(),
+            self.cacher.as_ref(),
+        ) {
+            ok_macro!(
+                self,
+                cacher.borrow_mut().cacher_mut().cache_update_and_test_path(
+                    &request.host,
+                    &self.forward_host,
+                    &request.path,
+                    &response,
+                )
+            );
+        }
+        if self.response_close {
+            //Pipelined requests after this one will never be answered.
+            if !self.pending_requests.is_empty() {
+                self.close_when_sent = true;
+                self.pending_requests.clear();
+            }
+            self.close_forward_stream(registry);
+        }
+        if request.map(|r| r.close).unwrap_or(false) {
+            self.close_when_sent = true;
+        }
+    }
+
+    // The backend closed the connection. A response without a length ends here, anything
+    // else cut short means the client connection can't be trusted any more.
+    fn forward_closed(&mut self, registry: &Registry) {
+        self.forward_eof = false;
+        match self.response_body.as_mut().map(|body| body.close()) {
+            Some(true) => self.finish_response(registry),
+            Some(false) => {
+                error!(target: &self.server_token.0.to_string(),"Backend {} closed in the middle of a response",self.forward_host);
+                self.close_when_sent = true;
+            }
+            None => (),
+        }
+        if !self.pending_requests.is_empty() || self.passthrough {
+            self.close_when_sent = true;
+        }
+        self.response_body = None;
+        self.response_cache = None;
+        self.pending_requests.clear();
+        self.close_forward_stream(registry);
+    }
+
+    fn close_forward_stream(&mut self, registry: &Registry) {
+        if let Some(forward_stream) = self.forward_stream.as_mut() {
+            ok_macro!(self, forward_stream.shutdown(net::Shutdown::Both));
+            ok_macro!(self, forward_stream.deregister(registry));
+        }
+        self.forward_stream = None;
+        self.forward_tls = None;
+        self.forward_preamble.clear();
     }
 }
 
 impl ConnectionSource {
-    fn send_301_reply(&mut self) -> bool {
+    fn redirect_reply(&self, host: &str, path: &str) -> Option<Vec<u8>> {
         if self.server_stream.local_addr().unwrap().port() == 80
             && dotenv::var("HTTP_REDIRECT_TO_HTTPS")
                 .unwrap_or(String::from(""))
@@ -454,25 +1087,61 @@ impl ConnectionSource {
                 let ignore_list = ignore_list.split(';');
 
                 for i in ignore_list {
-                    if !i.is_empty() && self.request_host.contains(i) {
-                        println!("I is: {:?} ({:?})", i, self.request_host);
-                        return false;
+                    if !i.is_empty() && host.contains(i) {
+                        println!("I is: {:?} ({:?})", i, host);
+                        return None;
                     }
                 }
             }
 
             let resp = format!(
                 "HTTP/1.1 301 Moved Permanently\r\nLocation: https://{}{}\r\n\r\n",
-                &self.request_host, &self.http_get_path
+                host, path
             );
-            let buf_rep: Vec<u8> = resp.clone().into_bytes();
-            self.send_to_client.push_back(buf_rep.clone());
-            //println!("{}",resp);
-            self.http_writer();
-            return true;
+            return Some(resp.into_bytes());
+        }
+        None
+    }
+    // Ok while the connect is still going on, forward_connected is set once it is done.
+    fn check_forward_connect(&mut self) -> io::Result<()> {
+        let forward_stream = match self.forward_stream.as_ref() {
+            Some(forward_stream) => forward_stream,
+            None => return Ok(()),
+        };
+        if let Some(e) = forward_stream.take_error()? {
+            return Err(e);
+        }
+        match forward_stream.peer_addr() {
+            Ok(_) => {
+                self.forward_connected = true;
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            Err(e) => Err(e),
         }
-        false
     }
+
+    // The backend can't be reached.
+    fn bad_gateway(&mut self) {
+        self.refuse_pending(BAD_GATEWAY_REPLY);
+    }
+
+    // The requests waiting for the backend get reply instead and the connection is closed,
+    // the rest of their bodies is dropped. Passthrough bytes have no one to answer them.
+    fn refuse_pending(&mut self, reply: &[u8]) {
+        self.send_to_farward.clear();
+        self.forward_preamble.clear();
+        self.forward_tls = None;
+        self.discard_request_body = true;
+        if self.passthrough || self.pending_requests.is_empty() {
+            self.closing = true;
+            return;
+        }
+        self.pending_requests.clear();
+        self.send_to_client.push_back(reply.to_vec());
+        self.close_when_sent = true;
+    }
+
     //Used by read above to start a forward_stream to handle sending along the request to
     //the backend host, and to then read the reply and send along to the server_stream that
     //then in the above write function will send it to the client.
@@ -489,33 +1158,64 @@ impl ConnectionSource {
         //it, else we need to reregister.
         if self.forward_stream.is_none() {
             self.forward_stream = if !self.request_host.is_empty() {
-                self.forward_host = self
-                    .forward_lookup
-                    .get(&self.request_host)
-                    .unwrap_or(&dotenv::var("DEFAULT_FORWARD").unwrap_or(String::from("")))
-                    //                    .unwrap_or(&String::from("192.168.96.54:80"))
-                    .parse()
-                    .expect("what2");
+                self.forward_host = self.lookup_forward(&self.request_host);
 
                 info!(target: &self.server_token.0.to_string(),
                     "Connection established {} -> {}:{} => {}",
-                    self.server_stream.peer_addr().expect("Peer_Addr").to_string(),
+                    self.peer_addr().expect("Peer_Addr").to_string(),
                       self.request_host, self.server_stream.local_addr().expect("ServerStream").port(),self.forward_host);
 
-                let socket: Result<std::net::SocketAddr, _> = self.forward_host.parse();
-                if socket.is_ok() {
-                    Some(TcpStream::connect(socket.unwrap()).unwrap())
+                let target = ForwardTarget::parse(&self.forward_host);
+                self.forward_tls = None;
+                if let Ok(socket) = self.forward_lookup.socket_addr(&target) {
+                    if target.is_https() {
+                        match self.upstream_tls.session(&target) {
+                            Ok(session) => self.forward_tls = Some(session),
+                            Err(e) => {
+                                error!(target: &self.server_token.0.to_string(),"No TLS to backend {}: {}",self.forward_host,e);
+                                self.bad_gateway();
+                                return false;
+                            }
+                        }
+                    }
+                    //The PROXY header has to be the first thing the backend sees on every
+                    //new backend connection, so it goes in front of the queued request.
+                    if let Some(version) = target.proxy_protocol {
+                        match (self.peer_addr(), self.server_stream.local_addr()) {
+                            (Ok(source), Ok(destination)) => {
+                                let header = proxy_header(version, source, destination);
+                                if self.forward_tls.is_some() {
+                                    self.forward_preamble = header;
+                                } else {
+                                    self.send_to_farward.push_front(header);
+                                }
+                            }
+                            (source, destination) => {
+                                error!(target: &self.server_token.0.to_string(),"No adresses for PROXY header {:?} {:?}",source,destination);
+                            }
+                        }
+                    }
+                    match TcpStream::connect(socket) {
+                        Ok(stream) => {
+                            self.forward_connected = false;
+                            Some(stream)
+                        }
+                        Err(e) => {
+                            error!(target: &self.server_token.0.to_string(),"Unable to connect to {}: {}",self.forward_host,e);
+                            self.bad_gateway();
+                            return false;
+                        }
+                    }
                 } else {
-                    self.closing = true;
-                    error!(target: &self.server_token.0.to_string(),"We have no forwarding adress for {}",&self.request_host);
-                    None
+                    error!(target: &self.server_token.0.to_string(),"We have no forwarding adress for {}: {}",&self.request_host,
+                        self.forward_lookup.socket_addr(&target).err().unwrap_or_default());
+                    self.bad_gateway();
+                    return false;
                 }
             } else {
-                //Anything else are assigned the following port and IP
-                error!(target: &self.server_token.0.to_string(),"Could not find forward adress return with false!");
+                error!(target: &self.server_token.0.to_string(),"Could not find forward adress, no host to route on");
+                self.refuse_pending(BAD_REQUEST_REPLY);
                 return false;
-                //TODO: fix, should either not be used or should be in config
-                //Some(TcpStream::connect("192.168.96.54:80".parse().unwrap()).unwrap())
             };
 
             //We need to register the forward stream, as it is newly created, or recreated.
@@ -556,28 +1256,6 @@ impl ConnectionSource {
         event: &Event,
         token: Token,
     ) -> Option<bool> {
-        let lib = match libloading::Library::new(
-            dotenv::var("SNI_CACHE_PLUGIN").unwrap_or(String::from("")),
-        ) {
-            Ok(a) => Some(a),
-            Err(e) => {
-                info!(target: "0","No certificate plugin to load! Error: \r\n {:?}",e);
-                debug!(target: "0","Error: \r\n {:?}",e);
-                None
-            }
-        };
-        let ch: Option<Box<dyn Cacher>> = if lib.is_some() {
-            let get_cacher: libloading::Symbol<fn() -> Box<dyn Cacher>> =
-                unsafe { lib.as_ref().unwrap().get(b"get_cacher") }.expect("load symbol");
-            let ch = get_cacher();
-            Some(ch)
-        //Create an arc of the forwards
-        } else {
-            None
-        };
-
-        let mut cacher = ch.unwrap();
-
         // if token == self.server_token {
         //     self.server_stream.deregister(registry).expect("Expected to deregister server thread on entry!");
         // }
@@ -586,11 +1264,31 @@ impl ConnectionSource {
         //     self.forward_stream.as_mut().unwrap().deregister(registry).expect("Expected to deregister server thread on entry!");
         // }
 
-        // Too much trace!(target: &self.server_token.0.to_string(),"Main incomming Event: \r\n{:?}",event);
-        //TODO set config for connection timout
-        if self.activity_timeout.is_some()
-            && self.activity_timeout.unwrap().elapsed().as_millis() > 3000
+        //Behind a load balancer the PROXY header comes before anything else.
+        if self.expect_proxy_header && token == self.server_token && event.is_readable() {
+            if !self.read_proxy_header() {
+                return Some(true);
+            }
+            if self.closing {
+                self.close_all(registry);
+                return Some(false);
+            }
+        }
+
+        //Before rustls gets any bytes we look at the ClientHello, if the SNI host is a
+        //passthrough forward we stop doing TLS for this connection.
+        if self.do_tls
+            && !self.client_hello_checked
+            && token == self.server_token
+            && event.is_readable()
+            && !self.check_client_hello()
         {
+            //No reregister needed, new data from the client gives us a new event.
+            return Some(true);
+        }
+
+        // Too much trace!(target: &self.server_token.0.to_string(),"Main incomming Event: \r\n{:?}",event);
+        if self.is_idle_too_long() {
             self.closing = true;
         }
 
@@ -608,7 +1306,7 @@ impl ConnectionSource {
         let fwd_ok_w = forward
             && event.is_writable()
             && self.forward_stream.is_some()
-            && self.send_to_farward.len() > 0;
+            && self.forward_wants_write();
         //&& self.do_tls;
 
         // let http_fwd_ok_r =
@@ -638,19 +1336,6 @@ impl ConnectionSource {
         //if fwd_ok_w is true we are ok to write to the client via server_stream
         let http_ok_w = event.is_writable() && !self.do_tls && self.send_to_client.len() > 0;
 
-        // Workaround for not finding a way to get tls to work in MIO ekosystem statemachine environment,
-        // and seem to need read write on for the socket all the time.
-        if !forward
-            && event.is_writable()
-            && self.send_to_client.len() > 0
-            && !tls_ok_r
-            && !tls_ok_w
-        {
-            self.reregister(registry, token, Interest::READABLE | Interest::WRITABLE)
-                .ok();
-            return Some(true);
-        };
-
         trace!(target: &self.server_token.0.to_string(),
             "Main Is clientThread ({}) or Is forwardThread ({})",
             token == self.server_token,
@@ -667,19 +1352,41 @@ impl ConnectionSource {
         //wait for client to reconnect. However forward_stream should be recreated
         //if it is needed.
 
-        if forward && event.is_error() {
-            ok_macro!(
-                self,
-                self.forward_stream
-                    .as_mut()
-                    .unwrap()
-                    .shutdown(net::Shutdown::Both)
-            );
+        //A connect is non-blocking, a refused one shows up on the first event of the new
+        //backend connection.
+        if forward && !self.forward_connected {
+            if let Err(e) = self.check_forward_connect() {
+                error!(target: &self.server_token.0.to_string(),"Unable to connect to {}: {}",self.forward_host,e);
+                self.close_forward_stream(registry);
+                self.bad_gateway();
+                ok_macro!(
+                    self,
+                    self.reregister(
+                        registry,
+                        self.server_token,
+                        Interest::READABLE | Interest::WRITABLE
+                    )
+                );
+                return Some(true);
+            }
+        }
+
+        if forward && (event.is_error() || event.is_write_closed() || event.is_read_closed()) {
+            trace!(target: &self.server_token.0.to_string(),"Forward closed or in error state");
+            //Whatever the backend managed to send before closing still goes to the client.
+            if !event.is_error() && self.forward_stream.is_some() && !self.forward_eof {
+                self.http_fwd_reader();
+                self.frame_responses(registry);
+            }
+            self.forward_closed(registry);
             ok_macro!(
                 self,
-                self.forward_stream.as_mut().unwrap().deregister(registry)
+                self.reregister(
+                    registry,
+                    self.server_token,
+                    Interest::READABLE | Interest::WRITABLE
+                )
             );
-            self.forward_stream = None;
             return Some(true);
         } else if !forward && event.is_error() {
             error!(target: &self.server_token.0.to_string(),"Socket is in error state! Closing");
@@ -689,21 +1396,7 @@ impl ConnectionSource {
 
         //If there is a close event on the socket we need to free forward so it is recreated,
         //And if server_stream we need to close everything.
-        if forward && event.is_write_closed() {
-            ok_macro!(
-                self,
-                self.forward_stream
-                    .as_mut()
-                    .unwrap()
-                    .shutdown(net::Shutdown::Both)
-            );
-            ok_macro!(
-                self,
-                self.forward_stream.as_mut().unwrap().deregister(registry)
-            );
-            self.forward_stream = None;
-            return Some(true);
-        } else if event.is_write_closed() {
+        if event.is_write_closed() {
             trace!(target: &self.server_token.0.to_string(),"Main Event is_write_closed");
             if forward {
                 self.closing = true;
@@ -715,21 +1408,7 @@ impl ConnectionSource {
 
         //If there is a close event on the socket we need to free forward so it is recreated,
         //And if server_stream we need to close everything.
-        if forward && event.is_read_closed() {
-            ok_macro!(
-                self,
-                self.forward_stream
-                    .as_mut()
-                    .unwrap()
-                    .shutdown(net::Shutdown::Both)
-            );
-            ok_macro!(
-                self,
-                self.forward_stream.as_mut().unwrap().deregister(registry)
-            );
-            self.forward_stream = None;
-            return Some(true);
-        } else if event.is_read_closed() {
+        if event.is_read_closed() {
             trace!(target: &self.server_token.0.to_string(),"Main Event is_read_closed");
             if forward {
                 self.forward_stream = None;
@@ -788,34 +1467,43 @@ impl ConnectionSource {
             trace!(target: &self.server_token.0.to_string(),"Entering FWD_R ({})", success);
 
             if self.http_fwd_reader() {
-                //let mut c = Cacher::new();
-                cacher
-                    .cache_update_and_test_path(
-                        &self.request_host,
-                        &self.forward_host,
-                        &self.http_get_path,
-                        &self.buf_client.clone(),
+                self.frame_responses(registry);
+                if self.forward_eof {
+                    self.forward_closed(registry);
+                }
+                ok_macro!(
+                    self,
+                    self.reregister(
+                        registry,
+                        self.server_token,
+                        Interest::READABLE | Interest::WRITABLE
                     )
-                    .expect("Expected cacher to cache_this");
-
-                //try_iterate_bytes(self.buf_client.clone());
-
-                &self.send_to_client.push_back(self.buf_client.clone());
-                &self.buf_client.clear();
+                );
             } else {
                 success = false;
             }
-            ok_macro!(
-                self,
-                self.reregister(
-                    registry,
-                    self.forward_token,
-                    Interest::READABLE | Interest::WRITABLE
-                )
-            );
+            if self.forward_stream.is_some() {
+                ok_macro!(
+                    self,
+                    self.reregister(
+                        registry,
+                        self.forward_token,
+                        Interest::READABLE | Interest::WRITABLE
+                    )
+                );
+            }
 
             trace!(target: &self.server_token.0.to_string(),"Exiting FWD_R ({})", success);
         } //DONE fwd_ok_r
+
+        //A request for another backend might be waiting for the previous one to finish.
+        if forward
+            && self.send_to_farward.is_empty()
+            && self.pending_requests.is_empty()
+            && !self.buf_forward.is_empty()
+        {
+            self.route_requests(registry);
+        }
         trace!(target: &self.server_token.0.to_string(),"MAIN closing: ({})", self.closing);
 
         /*
@@ -874,25 +1562,7 @@ impl ConnectionSource {
             trace!(target: &self.server_token.0.to_string(),"Entering CLI_R ({})", success);
             if self.https_reader() {
                 //Finished
-                if self.set_forward_adress() {
-                    //let c = Cacher::new();
-
-                    let res = cacher.cache_read_path(&self.request_host, &self.http_get_path);
-                    let opt = res.clone();
-
-                    //opt
-
-                    if opt.is_some() {
-                        let cache = opt.unwrap();
-                        //let cache = cache.clone().unwrap();
-                        self.send_to_client.push_back(cache);
-                        &self.buf_forward.clear();
-                    } else {
-                        &self.send_to_farward.push_back(self.buf_forward.clone());
-                        &self.buf_forward.clear();
-                        self.activate_forward_stream(registry);
-                    }
-                }
+                self.route_requests(registry);
             } else {
                 success = false;
             }
@@ -910,16 +1580,7 @@ impl ConnectionSource {
         if http_ok_r {
             trace!(target: &self.server_token.0.to_string(),"Entering HTTP_R ({})", success);
             if self.http_reader() {
-                if self.set_forward_adress() {
-                    if !self.send_301_reply() {
-                        &self.send_to_farward.push_back(self.buf_forward.clone());
-                        &self.buf_forward.clear();
-                        self.activate_forward_stream(registry);
-                    } else {
-                        &self.buf_forward.clear();
-                        self.activate_forward_stream(registry);
-                    }
-                }
+                self.route_requests(registry);
 
                 trace!(target: &self.server_token.0.to_string(),"HTTP_R finished reading http");
             } else {
@@ -941,6 +1602,19 @@ impl ConnectionSource {
 
         */
 
+        //The client asked for Connection: close, or the backend went away mid response.
+        //Close once everything we have for the client is written.
+        if self.close_when_sent
+            && self.send_to_client.is_empty()
+            && !self
+                .tls_session
+                .as_ref()
+                .map(|tls| tls.wants_write())
+                .unwrap_or(false)
+        {
+            self.closing = true;
+        }
+
         //If something has called for closing we will close everything with close_all
         //wich will set self.done_closing to true.
         if self.closing {
@@ -1026,3 +1700,121 @@ impl ConnectionSource {
         return Some(true);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::{net::TcpListener, Poll};
+    use std::collections::HashMap;
+
+    // A plain HTTP connection to us with one request already sent to the backend.
+    fn connection(listener: &TcpListener) -> ConnectionSource {
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let acme_challenges = AcmeChallenges::default();
+        acme_challenges
+            .write()
+            .unwrap()
+            .insert(String::from("token"), String::from("token.key"));
+        let mut connection = ConnectionSource::new(
+            stream,
+            Token(1),
+            Token(2),
+            None,
+            Arc::new(RoutingTable::new(&HashMap::new())),
+            None,
+            acme_challenges,
+            Arc::new(ClientAuthConfigs::default()),
+            Arc::new(UpstreamTlsConfigs::default()),
+            false,
+        );
+        connection.pending_requests.push_back(PendingRequest {
+            method: String::from("GET"),
+            host: String::from("a.test"),
+            path: String::from("/"),
+            close: false,
+        });
+        connection
+    }
+
+    #[test]
+    fn local_replies_wait_for_pipelined_responses() {
+        let poll = Poll::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut connection = connection(&listener);
+        let request = b"GET /.well-known/acme-challenge/token HTTP/1.1\r\nHost: a.test\r\n\r\n";
+        connection.buf_forward.extend_from_slice(request);
+
+        connection.route_requests(poll.registry());
+        assert!(connection.send_to_client.is_empty());
+        assert_eq!(connection.buf_forward, request.to_vec());
+
+        //The response to the first request is done.
+        connection.pending_requests.pop_front();
+        connection.route_requests(poll.registry());
+        let reply = connection.send_to_client.pop_front().unwrap();
+        assert!(reply.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(reply.ends_with(b"\r\n\r\ntoken.key"));
+        assert!(connection.buf_forward.is_empty());
+    }
+
+    #[test]
+    fn bad_requests_wait_for_pipelined_responses() {
+        let poll = Poll::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut connection = connection(&listener);
+        connection.buf_forward.extend_from_slice(b"GET / HTTP/1.1\r\nHost a.test\r\n\r\n");
+
+        connection.route_requests(poll.registry());
+        assert!(connection.send_to_client.is_empty());
+        assert!(!connection.close_when_sent);
+
+        connection.pending_requests.pop_front();
+        connection.route_requests(poll.registry());
+        assert_eq!(
+            connection.send_to_client.pop_front().unwrap(),
+            BAD_REQUEST_REPLY.to_vec()
+        );
+        assert!(connection.close_when_sent);
+    }
+
+    #[test]
+    fn requests_without_host_are_refused() {
+        let poll = Poll::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut connection = connection(&listener);
+        connection.pending_requests.clear();
+        connection.buf_forward.extend_from_slice(b"GET / HTTP/1.1\r\nHost: \r\n\r\n");
+
+        connection.route_requests(poll.registry());
+        assert_eq!(
+            connection.send_to_client.pop_front().unwrap(),
+            BAD_REQUEST_REPLY.to_vec()
+        );
+        assert!(connection.close_when_sent);
+        assert!(connection.send_to_farward.is_empty());
+    }
+
+    #[test]
+    fn passthrough_forwards_are_not_used_for_http() {
+        let poll = Poll::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut connection = connection(&listener);
+        connection.pending_requests.clear();
+        let mut forwards = HashMap::new();
+        forwards.insert(
+            String::from("p.test"),
+            String::from("passthrough://127.0.0.1:443"),
+        );
+        connection.forward_lookup = Arc::new(RoutingTable::new(&forwards));
+        connection.buf_forward.extend_from_slice(b"GET / HTTP/1.1\r\nHost: p.test\r\n\r\n");
+
+        connection.route_requests(poll.registry());
+        assert_eq!(
+            connection.send_to_client.pop_front().unwrap(),
+            BAD_GATEWAY_REPLY.to_vec()
+        );
+        assert!(connection.close_when_sent);
+        assert!(connection.send_to_farward.is_empty());
+        assert!(connection.forward_stream.is_none());
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        mpsc::{channel, Receiver, Sender, TryIter},
+        Arc,
+    },
+    thread,
+};
+
+use interfaces::PluginCertificate;
+use mio::Waker;
+
+use crate::{
+    plugin_resolver::PluginResolver, routing::RoutingTable, upstream_tls::UpstreamTlsConfigs,
+};
+
+// A routing table with its backend names looked up, and the TLS configs for its
+// https:// forwards.
+pub struct ResolvedForwards {
+    pub table: RoutingTable,
+    pub upstream_tls: UpstreamTlsConfigs,
+}
+
+impl ResolvedForwards {
+    // Blocks on DNS and on reading certificate files.
+    pub fn resolve(mut table: RoutingTable, previous: &UpstreamTlsConfigs) -> ResolvedForwards {
+        table.resolve();
+        let upstream_tls = UpstreamTlsConfigs::load(table.https_targets(), previous);
+        ResolvedForwards {
+            table,
+            upstream_tls,
+        }
+    }
+}
+
+// What the main loop sends the forward resolver thread.
+struct Request {
+    table: RoutingTable,
+    previous: Arc<UpstreamTlsConfigs>,
+    certificates: Option<Vec<PluginCertificate>>,
+}
+
+// The main loop end of the thread new routing tables are resolved on, so a reload or new
+// forwards from the plugin never hold up the connections. New certificates from the
+// plugin are parsed there too and go straight to the PluginResolver. Finished tables come
+// back through resolved() after the waker fires, in the order they were sent.
+pub struct ForwardResolver {
+    requests: Sender<Request>,
+    resolved: Receiver<ResolvedForwards>,
+}
+
+impl ForwardResolver {
+    pub fn start(waker: Arc<Waker>, plugin_resolver: Arc<PluginResolver>) -> ForwardResolver {
+        let (request_tx, request_rx) = channel::<Request>();
+        let (resolved_tx, resolved_rx) = channel();
+        thread::spawn(move || {
+            while let Ok(request) = request_rx.recv() {
+                let resolved = match catch_unwind(AssertUnwindSafe(|| {
+                    if let Some(certificates) = request.certificates.as_ref() {
+                        plugin_resolver.update(certificates);
+                    }
+                    ResolvedForwards::resolve(request.table, &request.previous)
+                })) {
+                    Ok(resolved) => resolved,
+                    Err(_) => {
+                        error!(target: "0","Resolving forwards panicked, keeping the current forwards");
+                        continue;
+                    }
+                };
+                if resolved_tx.send(resolved).is_err() {
+                    return;
+                }
+                if let Err(e) = waker.wake() {
+                    error!(target: "0","Unable to wake the main loop with new forwards: {}",e);
+                }
+            }
+        });
+        ForwardResolver {
+            requests: request_tx,
+            resolved: resolved_rx,
+        }
+    }
+
+    // previous is what the table replaces, its TLS configs are kept for the same files.
+    // certificates are the plugin's, None when they haven't changed.
+    pub fn resolve(
+        &self,
+        table: RoutingTable,
+        previous: Arc<UpstreamTlsConfigs>,
+        certificates: Option<Vec<PluginCertificate>>,
+    ) {
+        let request = Request {
+            table,
+            previous,
+            certificates,
+        };
+        if self.requests.send(request).is_err() {
+            error!(target: "0","The forward resolver has stopped, keeping the current forwards");
+        }
+    }
+
+    pub fn resolved(&self) -> TryIter<'_, ResolvedForwards> {
+        self.resolved.try_iter()
+    }
+}
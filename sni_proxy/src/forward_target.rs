@@ -0,0 +1,140 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use crate::proxy_protocol::ProxyProtocolVersion;
+
+// A forward target is the value in the forward lookup, for most hosts just "ip:port"
+// where we terminate TLS and talk plain HTTP to the backend. It can be prefixed with
+// a scheme to change how the connection is forwarded:
+//
+//   192.168.1.10:80                 TLS terminated here, HTTP to the backend.
+//   passthrough://192.168.1.10:443  TLS is not terminated, the backend owns the
+//                                   certificate and gets the raw encrypted bytes.
+//   https://backend.example:8443    TLS terminated here and HTTP over a new TLS
+//                                   connection to the backend (default port 443).
+//
+// Options for the backend connection can be added as a query string:
+//
+//   192.168.1.10:80?proxy=v2        Send a PROXY protocol v1/v2 header first.
+//   https://10.0.0.5?sni=b.example  The name sent in SNI and checked against the
+//                                   backend certificate, needed when the host is an IP.
+//   https://b.example?ca=ca.pem     CAs the backend certificate must be issued by,
+//                                   else UPSTREAM_CA_FILE or the system bundle.
+//   https://b.example?cert=c.pem&key=c.key  Client certificate for the backend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForwardMode {
+    Http,
+    Https,
+    Passthrough,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardTarget {
+    pub mode: ForwardMode,
+    pub address: String,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    pub sni: Option<String>,
+    pub ca_file: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+const PASSTHROUGH_SCHEME: &str = "passthrough://";
+const HTTPS_SCHEME: &str = "https://";
+const HTTPS_PORT: &str = "443";
+
+impl ForwardTarget {
+    pub fn parse(target: &str) -> ForwardTarget {
+        let target = target.trim();
+        let (mode, target) = if let Some(target) = target.strip_prefix(PASSTHROUGH_SCHEME) {
+            (ForwardMode::Passthrough, target)
+        } else if let Some(target) = target.strip_prefix(HTTPS_SCHEME) {
+            (ForwardMode::Https, target)
+        } else {
+            (ForwardMode::Http, target)
+        };
+
+        let (address, options) = match target.find('?') {
+            Some(i) => (&target[..i], &target[i + 1..]),
+            None => (target, ""),
+        };
+        let address = match (&mode, address.rfind(':')) {
+            (ForwardMode::Https, None) => format!("{}:{}", address, HTTPS_PORT),
+            (ForwardMode::Https, Some(i)) if address[i..].contains(']') => {
+                format!("{}:{}", address, HTTPS_PORT)
+            }
+            _ => String::from(address),
+        };
+
+        let mut forward_target = ForwardTarget {
+            mode,
+            address,
+            proxy_protocol: None,
+            sni: None,
+            ca_file: None,
+            client_cert: None,
+            client_key: None,
+        };
+        for option in options.split('&').filter(|o| !o.is_empty()) {
+            let (key, value) = match option.find('=') {
+                Some(i) => (&option[..i], &option[i + 1..]),
+                None => (option, ""),
+            };
+            match key {
+                "proxy" => {
+                    forward_target.proxy_protocol = ProxyProtocolVersion::parse(value);
+                    if forward_target.proxy_protocol.is_none() {
+                        warn!(target: "0","Unknown PROXY protocol version {:?} for forward {}",value,forward_target.address);
+                    }
+                }
+                "sni" => forward_target.sni = Some(String::from(value)),
+                "ca" => forward_target.ca_file = Some(String::from(value)),
+                "cert" => forward_target.client_cert = Some(String::from(value)),
+                "key" => forward_target.client_key = Some(String::from(value)),
+                _ => {
+                    warn!(target: "0","Unknown option {:?} for forward {}",key,forward_target.address);
+                }
+            }
+        }
+        forward_target
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.mode == ForwardMode::Passthrough
+    }
+
+    pub fn is_https(&self) -> bool {
+        self.mode == ForwardMode::Https
+    }
+
+    // Only set when the address is an IP and port, names are looked up with resolve().
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.address.parse().ok()
+    }
+
+    // https:// backends can be given by name.
+    pub fn needs_lookup(&self) -> bool {
+        self.is_https() && self.socket_addr().is_none()
+    }
+
+    // Blocks, so it is done on the ForwardResolver thread and never in the event loop.
+    pub fn resolve(&self) -> Result<SocketAddr, String> {
+        self.address
+            .to_socket_addrs()
+            .map_err(|e| format!("Unable to look up {}: {}", self.address, e))?
+            .next()
+            .ok_or_else(|| format!("No address for {}", self.address))
+    }
+
+    // The name we expect on the backend certificate.
+    pub fn server_name(&self) -> Option<&str> {
+        if let Some(sni) = self.sni.as_deref() {
+            return Some(sni);
+        }
+        let host = &self.address[..self.address.rfind(':')?];
+        if host.starts_with('[') || host.parse::<std::net::IpAddr>().is_ok() {
+            None
+        } else {
+            Some(host)
+        }
+    }
+}
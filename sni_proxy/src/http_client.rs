@@ -0,0 +1,255 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::{ClientConfig, ClientSession, StreamOwned};
+
+use crate::http_parser::{parse_response_head, BodyLength, NUM_OF_HEADERS};
+
+// The system bundle on Debian/Ubuntu, what we trust unless told otherwise.
+pub const DEFAULT_CA_FILE: &str = "/etc/ssl/certs/ca-certificates.crt";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+//What we talk to sends JSON documents and certificate chains, nothing near this.
+const MAX_RESPONSE: u64 = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+// A blocking HTTP/1.1 client for the background threads that talk to other servers, one
+// connection per request. It waits on the network, never use it from the mio loop.
+pub struct HttpClient {
+    tls_config: Arc<ClientConfig>,
+}
+
+impl HttpClient {
+    // https:// servers must have a certificate from one of the CAs in ca_file (PEM).
+    pub fn new(ca_file: &str) -> Result<HttpClient, String> {
+        let mut config = ClientConfig::new();
+        let file = File::open(ca_file).map_err(|e| format!("Unable to open {}: {}", ca_file, e))?;
+        let (added, _) = config
+            .root_store
+            .add_pem_file(&mut BufReader::new(file))
+            .map_err(|_| format!("Unable to read CA certificates from {}", ca_file))?;
+        if added == 0 {
+            return Err(format!("No CA certificates in {}", ca_file));
+        }
+        Ok(HttpClient {
+            tls_config: Arc::new(config),
+        })
+    }
+
+    pub fn get(&self, url: &str) -> Result<HttpResponse, String> {
+        self.request("GET", url, None)
+    }
+
+    pub fn post(&self, url: &str, content_type: &str, body: &[u8]) -> Result<HttpResponse, String> {
+        self.request("POST", url, Some((content_type, body)))
+    }
+
+    pub fn request(
+        &self,
+        method: &str,
+        url: &str,
+        content: Option<(&str, &[u8])>,
+    ) -> Result<HttpResponse, String> {
+        let url_parts = UrlParts::parse(url)?;
+        let raw = self
+            .exchange(&url_parts, &request_bytes(method, &url_parts, content))
+            .map_err(|e| format!("{} {}: {}", method, url, e))?;
+        parse_response(&raw, method).map_err(|e| format!("{} {}: {}", method, url, e))
+    }
+
+    fn exchange(&self, url: &UrlParts, request: &[u8]) -> io::Result<Vec<u8>> {
+        let address = (url.host.as_str(), url.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for host"))?;
+        let stream = TcpStream::connect_timeout(&address, HTTP_TIMEOUT)?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+        if url.tls {
+            let name = webpki::DNSNameRef::try_from_ascii_str(&url.host)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not a DNS name"))?;
+            let session = ClientSession::new(&self.tls_config, name);
+            let mut stream = StreamOwned::new(session, stream);
+            stream.write_all(request)?;
+            read_until_close(&mut stream)
+        } else {
+            let mut stream = stream;
+            stream.write_all(request)?;
+            read_until_close(&mut stream)
+        }
+    }
+}
+
+// We ask for Connection: close, so the response ends when the server closes.
+fn read_until_close(stream: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let mut raw = Vec::new();
+    match stream.take(MAX_RESPONSE).read_to_end(&mut raw) {
+        Ok(_) => Ok(raw),
+        //Servers that close without a TLS close_notify, the framing tells if we got it all.
+        Err(e) if e.kind() == io::ErrorKind::ConnectionAborted && !raw.is_empty() => Ok(raw),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !raw.is_empty() => Ok(raw),
+        Err(e) => Err(e),
+    }
+}
+
+fn request_bytes(method: &str, url: &UrlParts, content: Option<(&str, &[u8])>) -> Vec<u8> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: sni_proxy/{}\r\nAccept: */*\r\nConnection: close\r\n",
+        method,
+        url.path,
+        url.host_header,
+        env!("CARGO_PKG_VERSION")
+    );
+    let body: &[u8] = match content {
+        Some((content_type, body)) => {
+            head.push_str(&format!(
+                "Content-Type: {}\r\nContent-Length: {}\r\n",
+                content_type,
+                body.len()
+            ));
+            body
+        }
+        None => &[],
+    };
+    head.push_str("\r\n");
+    let mut request = head.into_bytes();
+    request.extend_from_slice(body);
+    request
+}
+
+fn parse_response(raw: &[u8], method: &str) -> Result<HttpResponse, String> {
+    let head = parse_response_head(raw, method)?
+        .ok_or_else(|| String::from("Connection closed before the response head"))?;
+    let mut header_buf = [httparse::EMPTY_HEADER; NUM_OF_HEADERS];
+    let mut response = httparse::Response::new(&mut header_buf);
+    response.parse(raw).map_err(|e| format!("{:?}", e))?;
+    let headers = response
+        .headers
+        .iter()
+        .map(|h| {
+            (
+                String::from(h.name),
+                String::from_utf8_lossy(h.value).trim().to_string(),
+            )
+        })
+        .collect();
+
+    let rest = &raw[head.head_len..];
+    let body = match head.body {
+        BodyLength::Empty => Vec::new(),
+        BodyLength::ContentLength(n) if n <= rest.len() => rest[..n].to_vec(),
+        BodyLength::ContentLength(n) => {
+            return Err(format!("Body cut short, {} of {} bytes", rest.len(), n))
+        }
+        BodyLength::Chunked => decode_chunked(rest)?,
+        BodyLength::UntilClose => rest.to_vec(),
+    };
+    Ok(HttpResponse {
+        status: head.status,
+        headers,
+        body,
+    })
+}
+
+// Transfer-Encoding: chunked, trailers are ignored.
+fn decode_chunked(mut rest: &[u8]) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = rest
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| String::from("Chunked body cut short"))?;
+        let size_line = String::from_utf8_lossy(&rest[..line_end]);
+        let size_hex = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16)
+            .map_err(|_| format!("Bad chunk size {:?}", size_hex))?;
+        rest = &rest[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if rest.len() < size + 2 {
+            return Err(String::from("Chunked body cut short"));
+        }
+        body.extend_from_slice(&rest[..size]);
+        rest = &rest[size + 2..];
+    }
+}
+
+// scheme://host[:port][/path], all we need to know about the urls we are given.
+struct UrlParts {
+    tls: bool,
+    host: String,
+    //As it goes in the Host header, with the port if it was in the url.
+    host_header: String,
+    port: u16,
+    path: String,
+}
+
+impl UrlParts {
+    fn parse(url: &str) -> Result<UrlParts, String> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!("Unsupported url {}", url));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let end = bracketed
+                .find(']')
+                .ok_or_else(|| format!("Bad host in url {}", url))?;
+            let port = bracketed[end + 1..].strip_prefix(':');
+            (&bracketed[..end], port)
+        } else {
+            match authority.rfind(':') {
+                Some(i) => (&authority[..i], Some(&authority[i + 1..])),
+                None => (authority, None),
+            }
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("Bad port in url {}", url))?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(format!("No host in url {}", url));
+        }
+        Ok(UrlParts {
+            tls,
+            host: String::from(host),
+            host_header: String::from(authority),
+            port,
+            path: String::from(path),
+        })
+    }
+}
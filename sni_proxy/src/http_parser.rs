@@ -5,12 +5,10 @@
 // pub fn try_iterate_bytes(in_buf: Vec<u8>) {
 //     let buf = in_buf.clone();
 //     let buf = buf.as_slice();
-    
 
 //     let mut cursor = io::Cursor::new(buf);
 //     let mut lines = String::new();
 
-
 //     let _num_bytes = cursor
 //         .read_line(&mut lines)
 //         .expect("reading from cursor won't fail");
@@ -22,3 +20,690 @@
 // error!("===================================================================================================");
 
 // }
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+pub const NUM_OF_HEADERS: usize = 200;
+
+// Headers that tell the backend who the client is, we only let a client set
+// these if it is one of our TRUSTED_PROXIES.
+const FORWARDED_HEADERS: [&str; 4] = [
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "forwarded",
+];
+
+// The subject of the verified client certificate, never taken from the client.
+const CLIENT_CERT_SUBJECT_HEADER: &str = "X-Client-Cert-Subject";
+
+// Is the client allowed to send its own X-Forwarded-* and Forwarded headers.
+// TRUSTED_PROXIES is a ; separated list of ip adresses.
+pub fn is_trusted_proxy(client: &IpAddr) -> bool {
+    dotenv::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(';')
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .any(|ip| &ip == client)
+}
+
+// Rewrites the request head at the start of buf so the backend knows the real client.
+// Adds X-Forwarded-For, X-Forwarded-Proto, X-Forwarded-Host and Forwarded (RFC 7239),
+// client supplied copies are dropped unless trusted, then we append to them instead.
+// The Host only goes into X-Forwarded-Host and host= when it is a plain host[:port].
+// X-Client-Cert-Subject is client_subject when the client sent a certificate.
+// Returns None if buf does not start with a complete request head.
+pub fn add_forwarded_headers(
+    buf: &[u8],
+    client: SocketAddr,
+    proto: &str,
+    trusted: bool,
+    client_subject: Option<&str>,
+) -> Option<Vec<u8>> {
+    let mut headers = [httparse::EMPTY_HEADER; NUM_OF_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let head_len = match req.parse(buf) {
+        Ok(httparse::Status::Complete(head_len)) => head_len,
+        _ => return None,
+    };
+
+    let mut head: Vec<u8> = format!(
+        "{} {} HTTP/1.{}\r\n",
+        req.method.unwrap_or("GET"),
+        req.path.unwrap_or("/"),
+        req.version.unwrap_or(1)
+    )
+    .into_bytes();
+
+    let mut host = String::new();
+    let mut forwarded_for: Vec<String> = Vec::new();
+    let mut forwarded: Vec<String> = Vec::new();
+    let mut forwarded_proto: Option<String> = None;
+    let mut forwarded_host: Option<String> = None;
+    for h in req.headers.iter() {
+        let name = h.name.to_lowercase();
+        let value = String::from_utf8_lossy(h.value).trim().to_string();
+        if name == "host" {
+            host = value.clone();
+        }
+        if FORWARDED_HEADERS.contains(&name.as_str()) {
+            if trusted {
+                match name.as_str() {
+                    "x-forwarded-for" => forwarded_for.push(value),
+                    "forwarded" => forwarded.push(value),
+                    "x-forwarded-proto" => forwarded_proto = Some(value),
+                    _ => forwarded_host = Some(value),
+                }
+            }
+            continue;
+        }
+        if h.name.eq_ignore_ascii_case(CLIENT_CERT_SUBJECT_HEADER) {
+            continue;
+        }
+        head.extend_from_slice(h.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(h.value);
+        head.extend_from_slice(b"\r\n");
+    }
+
+    forwarded_for.push(client.ip().to_string());
+    //RFC 7239 wants v6 adresses quoted and in brackets
+    let node = match client.ip() {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    };
+    //The Host is the client's, anything but a plain host[:port] could break out of the
+    //quoted-string and add elements of its own, so it is left out.
+    let host = Some(host).filter(|host| is_valid_host(host));
+    match &host {
+        Some(host) => forwarded.push(format!("for={};proto={};host=\"{}\"", node, proto, host)),
+        None => forwarded.push(format!("for={};proto={}", node, proto)),
+    }
+
+    let mut added = format!(
+        "X-Forwarded-For: {}\r\nX-Forwarded-Proto: {}\r\n",
+        forwarded_for.join(", "),
+        forwarded_proto.unwrap_or_else(|| String::from(proto)),
+    );
+    if let Some(forwarded_host) = forwarded_host.or(host) {
+        added.push_str(&format!("X-Forwarded-Host: {}\r\n", forwarded_host));
+    }
+    added.push_str(&format!("Forwarded: {}\r\n", forwarded.join(", ")));
+    if let Some(client_subject) = client_subject {
+        added.push_str(&format!(
+            "{}: {}\r\n",
+            CLIENT_CERT_SUBJECT_HEADER, client_subject
+        ));
+    }
+    added.push_str("\r\n");
+    head.extend_from_slice(added.as_bytes());
+    head.extend_from_slice(&buf[head_len..]);
+    Some(head)
+}
+
+// A Host header value that is a host name, an IPv4 address or a bracketed IPv6
+// address, with an optional port. Stricter than RFC 3986 reg-name on purpose.
+fn is_valid_host(host: &str) -> bool {
+    let (name, port) = if host.starts_with('[') {
+        match host.find(']') {
+            Some(i) => (&host[1..i], &host[i + 1..]),
+            None => return false,
+        }
+    } else {
+        match host.find(':') {
+            Some(i) => (&host[..i], &host[i..]),
+            None => (host, ""),
+        }
+    };
+    let name_ok = if host.starts_with('[') {
+        name.parse::<Ipv6Addr>().is_ok()
+    } else {
+        !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+    };
+    let port_ok = port.is_empty()
+        || (port.len() > 1
+            && port.len() <= 6
+            && port.starts_with(':')
+            && port[1..].bytes().all(|b| b.is_ascii_digit()));
+    name_ok && port_ok
+}
+
+// How the length of a message body is decided, RFC 7230 section 3.3.3
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyLength {
+    Empty,
+    ContentLength(usize),
+    Chunked,
+    //Responses without a length end when the backend closes the connection.
+    UntilClose,
+}
+
+#[derive(Debug)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub host: String,
+    pub head_len: usize,
+    pub body: BodyLength,
+    //The client wants the connection closed after this request.
+    pub close: bool,
+}
+
+// Parses the request head at the start of buf. Ok(None) means we need more data.
+pub fn parse_request_head(buf: &[u8]) -> Result<Option<RequestHead>, String> {
+    let mut headers = [httparse::EMPTY_HEADER; NUM_OF_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let head_len = match req.parse(buf) {
+        Ok(httparse::Status::Complete(head_len)) => head_len,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(format!("{:?}", e)),
+    };
+
+    let host = header_value(req.headers, "host").unwrap_or_default();
+    Ok(Some(RequestHead {
+        method: String::from(req.method.unwrap_or("")),
+        path: String::from(req.path.unwrap_or("")),
+        host: host.to_lowercase(),
+        head_len,
+        body: body_length(req.headers, true)?,
+        close: connection_close(req.headers, req.version.unwrap_or(1)),
+    }))
+}
+
+#[derive(Debug)]
+pub struct ResponseHead {
+    pub status: u16,
+    pub head_len: usize,
+    pub body: BodyLength,
+    //The backend closes the connection after this response.
+    pub close: bool,
+}
+
+// Parses the response head at the start of buf, the request method is needed as a
+// response to HEAD never has a body. Ok(None) means we need more data.
+pub fn parse_response_head(
+    buf: &[u8],
+    request_method: &str,
+) -> Result<Option<ResponseHead>, String> {
+    let mut headers = [httparse::EMPTY_HEADER; NUM_OF_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    let head_len = match res.parse(buf) {
+        Ok(httparse::Status::Complete(head_len)) => head_len,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(format!("{:?}", e)),
+    };
+
+    let status = res.code.unwrap_or(0);
+    let body = if request_method.eq_ignore_ascii_case("HEAD")
+        || (100..200).contains(&status)
+        || status == 204
+        || status == 304
+    {
+        BodyLength::Empty
+    } else {
+        body_length(res.headers, false)?
+    };
+    Ok(Some(ResponseHead {
+        status,
+        head_len,
+        body,
+        close: connection_close(res.headers, res.version.unwrap_or(1)),
+    }))
+}
+
+pub fn header_value(headers: &[httparse::Header], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| String::from_utf8_lossy(h.value).trim().to_string())
+}
+
+// HTTP/1.1 keeps the connection unless told otherwise, HTTP/1.0 is the other way around.
+fn connection_close(headers: &[httparse::Header], version: u8) -> bool {
+    let connection = header_value(headers, "connection")
+        .unwrap_or_default()
+        .to_lowercase();
+    let mut tokens = connection.split(',').map(|t| t.trim());
+    if version == 0 {
+        !tokens.any(|t| t == "keep-alive")
+    } else {
+        tokens.any(|t| t == "close")
+    }
+}
+
+// RFC 7230 section 3.3.3. Without Transfer-Encoding or Content-Length a request has no
+// body and a response goes on until the connection is closed. Anything two parties could
+// read differently is an error, requests after it could end up at another backend.
+fn body_length(headers: &[httparse::Header], request: bool) -> Result<BodyLength, String> {
+    let values = |name: &str| -> Vec<String> {
+        headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case(name))
+            .flat_map(|h| {
+                String::from_utf8_lossy(h.value)
+                    .split(',')
+                    .map(|v| v.trim().to_lowercase())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    };
+    let transfer_encoding = values("transfer-encoding");
+    let content_length = values("content-length");
+
+    if !transfer_encoding.is_empty() {
+        if !content_length.is_empty() {
+            return Err(String::from("Both Transfer-Encoding and Content-Length"));
+        }
+        let chunked = transfer_encoding
+            .iter()
+            .filter(|te| *te == "chunked")
+            .count();
+        return match (chunked, transfer_encoding.last().map(String::as_str)) {
+            (1, Some("chunked")) => Ok(BodyLength::Chunked),
+            //Chunked must be last, and there only once.
+            (0, _) if !request => Ok(BodyLength::UntilClose),
+            _ => Err(format!(
+                "Bad Transfer-Encoding: {}",
+                transfer_encoding.join(", ")
+            )),
+        };
+    }
+    match content_length.as_slice() {
+        [] if request => Ok(BodyLength::Empty),
+        [] => Ok(BodyLength::UntilClose),
+        [cl] if !cl.is_empty() && cl.bytes().all(|b| b.is_ascii_digit()) => {
+            match cl.parse::<usize>() {
+                Ok(0) => Ok(BodyLength::Empty),
+                Ok(cl) => Ok(BodyLength::ContentLength(cl)),
+                Err(_) => Err(format!("Bad Content-Length: {}", cl)),
+            }
+        }
+        [cl] => Err(format!("Bad Content-Length: {}", cl)),
+        _ => Err(format!(
+            "More than one Content-Length: {}",
+            content_length.join(", ")
+        )),
+    }
+}
+
+#[derive(Debug)]
+enum BodyState {
+    Remaining(usize),
+    ChunkSize(Vec<u8>),
+    ChunkData(usize),
+    ChunkDataEnd(Vec<u8>),
+    Trailers(Vec<u8>),
+    UntilClose,
+    Done,
+}
+
+//A chunk size line or trailer longer than this is not something we want to buffer.
+const MAX_CHUNK_LINE: usize = 8192;
+
+// Keeps track of where a message body ends, without decoding or buffering it, so
+// that we can pass body bytes along as they arrive and know when the next message
+// starts.
+#[derive(Debug)]
+pub struct BodyFramer {
+    state: BodyState,
+}
+
+impl BodyFramer {
+    pub fn new(length: BodyLength) -> BodyFramer {
+        let state = match length {
+            BodyLength::Empty => BodyState::Done,
+            BodyLength::ContentLength(n) => BodyState::Remaining(n),
+            BodyLength::Chunked => BodyState::ChunkSize(Vec::new()),
+            BodyLength::UntilClose => BodyState::UntilClose,
+        };
+        BodyFramer { state }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, BodyState::Done)
+    }
+
+    // Called when the connection closed, returns true if that is a proper end of the
+    // body and false if the body was cut short.
+    pub fn close(&mut self) -> bool {
+        let complete = matches!(self.state, BodyState::Done | BodyState::UntilClose);
+        self.state = BodyState::Done;
+        complete
+    }
+
+    // Returns how many bytes at the start of buf belong to this body.
+    pub fn advance(&mut self, buf: &[u8]) -> Result<usize, String> {
+        let mut used = 0;
+        while used < buf.len() {
+            let rest = &buf[used..];
+            self.state = match std::mem::replace(&mut self.state, BodyState::Done) {
+                BodyState::Done => return Ok(used),
+                BodyState::UntilClose => {
+                    used = buf.len();
+                    BodyState::UntilClose
+                }
+                BodyState::Remaining(n) => {
+                    let take = n.min(rest.len());
+                    used += take;
+                    if n == take {
+                        BodyState::Done
+                    } else {
+                        BodyState::Remaining(n - take)
+                    }
+                }
+                BodyState::ChunkData(n) => {
+                    let take = n.min(rest.len());
+                    used += take;
+                    if n == take {
+                        BodyState::ChunkDataEnd(Vec::new())
+                    } else {
+                        BodyState::ChunkData(n - take)
+                    }
+                }
+                BodyState::ChunkSize(line) => match read_line(line, rest, &mut used)? {
+                    Line::Partial(line) => BodyState::ChunkSize(line),
+                    Line::Complete(line) => {
+                        let size = String::from_utf8_lossy(&line);
+                        //Anything after ; is a chunk extension.
+                        let size = size.split(';').next().unwrap_or("").trim();
+                        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                            return Err(format!("Bad chunk size: {:?}", size));
+                        }
+                        match usize::from_str_radix(size, 16) {
+                            Ok(0) => BodyState::Trailers(Vec::new()),
+                            Ok(n) => BodyState::ChunkData(n),
+                            Err(_) => return Err(format!("Bad chunk size: {:?}", size)),
+                        }
+                    }
+                },
+                BodyState::ChunkDataEnd(line) => match read_line(line, rest, &mut used)? {
+                    Line::Partial(line) => BodyState::ChunkDataEnd(line),
+                    Line::Complete(line) if line.is_empty() => BodyState::ChunkSize(Vec::new()),
+                    Line::Complete(_) => return Err(String::from("Missing CRLF after chunk")),
+                },
+                BodyState::Trailers(line) => match read_line(line, rest, &mut used)? {
+                    Line::Partial(line) => BodyState::Trailers(line),
+                    Line::Complete(line) if line.is_empty() => BodyState::Done,
+                    Line::Complete(_) => BodyState::Trailers(Vec::new()),
+                },
+            };
+        }
+        Ok(used)
+    }
+}
+
+enum Line {
+    Complete(Vec<u8>),
+    Partial(Vec<u8>),
+}
+
+// Collects a CRLF terminated line that may be split over several reads, a complete
+// line is given back without the line ending.
+fn read_line(mut line: Vec<u8>, buf: &[u8], used: &mut usize) -> Result<Line, String> {
+    match buf.iter().position(|b| *b == b'\n') {
+        Some(end) => {
+            line.extend_from_slice(&buf[..end]);
+            *used += end + 1;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if line.len() > MAX_CHUNK_LINE {
+                return Err(String::from("Chunk line too long"));
+            }
+            Ok(Line::Complete(line))
+        }
+        None => {
+            line.extend_from_slice(buf);
+            *used += buf.len();
+            if line.len() > MAX_CHUNK_LINE {
+                return Err(String::from("Chunk line too long"));
+            }
+            Ok(Line::Partial(line))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> SocketAddr {
+        "192.0.2.1:4000".parse().unwrap()
+    }
+
+    fn forwarded(head: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(head)
+            .lines()
+            .filter(|line| {
+                line.starts_with("Forwarded: ") || line.starts_with("X-Forwarded-Host: ")
+            })
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn host_goes_into_forwarded() {
+        for (host, expected) in &[
+            ("a.test", "a.test"),
+            ("A.test:8443", "A.test:8443"),
+            ("192.0.2.9", "192.0.2.9"),
+            ("[2001:db8::1]:443", "[2001:db8::1]:443"),
+        ] {
+            let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            let head = add_forwarded_headers(request.as_bytes(), client(), "https", false, None);
+            assert_eq!(
+                forwarded(&head.unwrap()),
+                vec![
+                    format!("X-Forwarded-Host: {}", expected),
+                    format!("Forwarded: for=192.0.2.1;proto=https;host=\"{}\"", expected)
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn malicious_host_is_left_out_of_forwarded() {
+        for host in &[
+            "a.test\", for=127.0.0.1;proto=\"https",
+            "a.test;for=127.0.0.1",
+            "a.test,for=127.0.0.1",
+            "a.test\\",
+            "a.test:443:1",
+            "a.test:",
+            "[::1",
+            "[a.test]",
+            "",
+        ] {
+            let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            let head = add_forwarded_headers(request.as_bytes(), client(), "http", false, None);
+            assert_eq!(
+                forwarded(&head.unwrap()),
+                vec!["Forwarded: for=192.0.2.1;proto=http"],
+                "{}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn client_cert_subject_from_the_client_is_dropped() {
+        let request = b"GET / HTTP/1.1\r\nHost: a.test\r\nX-Client-Cert-Subject: CN=admin\r\nx-client-cert-subject: CN=root\r\n\r\n";
+        for trusted in &[false, true] {
+            let head = add_forwarded_headers(request, client(), "http", *trusted, None).unwrap();
+            let head = String::from_utf8(head).unwrap();
+            assert!(
+                !head.to_lowercase().contains("x-client-cert-subject"),
+                "{}",
+                head
+            );
+        }
+    }
+
+    #[test]
+    fn client_cert_subject_is_the_verified_one() {
+        let request = b"GET / HTTP/1.1\r\nHost: a.test\r\nX-Client-Cert-Subject: CN=admin\r\n\r\n";
+        let head =
+            add_forwarded_headers(request, client(), "https", false, Some("CN=alice")).unwrap();
+        let head = String::from_utf8(head).unwrap();
+        assert_eq!(head.matches("X-Client-Cert-Subject").count(), 1);
+        assert!(head.contains("X-Client-Cert-Subject: CN=alice\r\n"));
+        assert!(head.ends_with("\r\n\r\n"));
+    }
+
+    fn request_body(request: &str) -> Result<BodyLength, String> {
+        parse_request_head(request.as_bytes()).map(|head| head.unwrap().body)
+    }
+
+    // Feeds body to a framer in pieces of `step` bytes, like reads from a socket, and
+    // gives back how much of it was body.
+    fn frame(length: BodyLength, body: &[u8], step: usize) -> Result<(usize, bool), String> {
+        let mut framer = BodyFramer::new(length);
+        let mut used = 0;
+        let mut end = 0;
+        while end < body.len() {
+            end = (end + step).min(body.len());
+            used += framer.advance(&body[used..end])?;
+            if framer.is_done() {
+                break;
+            }
+        }
+        Ok((used, framer.is_done()))
+    }
+
+    #[test]
+    fn body_length_of_requests() {
+        assert_eq!(
+            request_body("GET / HTTP/1.1\r\nHost: a.test\r\n\r\n"),
+            Ok(BodyLength::Empty)
+        );
+        assert_eq!(
+            request_body("POST / HTTP/1.1\r\nContent-Length: 0\r\n\r\n"),
+            Ok(BodyLength::Empty)
+        );
+        assert_eq!(
+            request_body("POST / HTTP/1.1\r\nContent-Length: 12\r\n\r\n"),
+            Ok(BodyLength::ContentLength(12))
+        );
+        assert_eq!(
+            request_body("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, Chunked\r\n\r\n"),
+            Ok(BodyLength::Chunked)
+        );
+    }
+
+    #[test]
+    fn ambiguous_request_bodies_are_refused() {
+        for request in &[
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: xchunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ] {
+            assert!(request_body(request).is_err(), "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn response_without_chunked_last_goes_until_close() {
+        let head =
+            parse_response_head(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n", "GET")
+                .unwrap()
+                .unwrap();
+        assert_eq!(head.body, BodyLength::UntilClose);
+        assert!(parse_response_head(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+            "GET"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn content_length_body() {
+        let body = b"hello worldGET / HTTP/1.1\r\n";
+        for step in 1..body.len() {
+            assert_eq!(
+                frame(BodyLength::ContentLength(11), body, step),
+                Ok((11, true))
+            );
+        }
+        assert_eq!(frame(BodyLength::Empty, body, 4), Ok((0, true)));
+    }
+
+    #[test]
+    fn chunked_body_with_extensions_and_trailers() {
+        let body = b"5;name=value\r\nhello\r\n6 ; other\r\n world\r\n0;last\r\nExpires: never\r\nX-Sum: 1\r\n\r\nGET / HTTP/1.1\r\n";
+        let len = body.len() - b"GET / HTTP/1.1\r\n".len();
+        //Every split, down to a byte at a time.
+        for step in 1..=body.len() {
+            assert_eq!(
+                frame(BodyLength::Chunked, body, step),
+                Ok((len, true)),
+                "step {}",
+                step
+            );
+        }
+    }
+
+    #[test]
+    fn chunked_body_without_trailers_waits_for_the_last_line() {
+        let body = b"3\r\nabc\r\n0\r\n";
+        assert_eq!(
+            frame(BodyLength::Chunked, body, body.len()),
+            Ok((body.len(), false))
+        );
+        assert_eq!(
+            frame(BodyLength::Chunked, b"3\r\nabc\r\n0\r\n\r\n", 3),
+            Ok((13, true))
+        );
+    }
+
+    #[test]
+    fn bad_chunked_bodies_are_errors() {
+        for body in &[
+            &b"x\r\n"[..],
+            b"+5\r\nhello\r\n0\r\n\r\n",
+            b"-1\r\n",
+            b"\r\n",
+            b"0x5\r\nhello\r\n0\r\n\r\n",
+            b"ffffffffffffffffffffffff\r\n",
+            b"3\r\nabcdef\r\n",
+        ] {
+            assert!(frame(BodyLength::Chunked, body, 2).is_err(), "{:?}", body);
+        }
+    }
+
+    #[test]
+    fn chunk_lines_are_limited() {
+        let mut body = b"5;".to_vec();
+        body.resize(MAX_CHUNK_LINE + 10, b'a');
+        assert!(frame(BodyLength::Chunked, &body, 100).is_err());
+        body.extend_from_slice(b"\r\n");
+        assert!(frame(BodyLength::Chunked, &body, body.len()).is_err());
+    }
+
+    #[test]
+    fn read_line_over_several_reads() {
+        let mut used = 0;
+        let line = match read_line(Vec::new(), b"ab", &mut used) {
+            Ok(Line::Partial(line)) => line,
+            _ => panic!("expected a partial line"),
+        };
+        assert_eq!(used, 2);
+        let mut used = 0;
+        match read_line(line, b"c\r\nrest", &mut used) {
+            Ok(Line::Complete(line)) => assert_eq!(line, b"abc"),
+            _ => panic!("expected a complete line"),
+        }
+        assert_eq!(used, 3);
+    }
+}
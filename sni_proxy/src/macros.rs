@@ -26,39 +26,6 @@ macro_rules! write_error_handling {
 //Handles read errors from socket stream
 #[macro_export]
 macro_rules! read_error_handling {
-    // This form normal socket errors and bellow is tls
-    ($self:ident, $ret:ident, $receiver:ident, $buf:ident,$wb:ident) => {
-
-                match $ret {
-                    Ok(0) => {
-                        trace!(target: &$self.server_token.0.to_string(),"MACRO Read reading zero closing:({})", $self.closing);
-                        //$self.closing =true;
-                        break;
-                    }
-                    Ok(n) => {
-                        trace!(target: &$self.server_token.0.to_string(),"MACRO Read Transfering read buffer to datacollecter received_data {}",n);
-                        $receiver.extend_from_slice(&$buf[..n]);
-                    }
-                    Err(e)if e.kind() == io::ErrorKind::WouldBlock => {
-                        trace!(target: &$self.server_token.0.to_string(),"MACRO Read Would block\r\n{:?}",e);
-                        //TODO: Add a collector from client instead... so we dont halt the MIO loop or implement threading/async
-                        //break;
-                        if $wb.elapsed().as_millis() > 20 {
-                            break;
-                        }
-                        continue;
-                    }
-                    Err(e)if e.kind() == io::ErrorKind::Interrupted => {
-                        trace!(target: &$self.server_token.0.to_string(),"MACRO Read Interupted\r\n{:?}",e);
-                        continue;
-                    }
-                    Err(e) => {
-                        error!(target: &$self.server_token.0.to_string(),"MACRO Read Unknown error : {:?}", e);
-                        $self.closing = true;
-                        return false;
-                    },
-                }
-    };
     //Her is the handling for TLS socket/session
     ($self:ident, $ret:ident) => {
             match $ret {
@@ -21,10 +21,28 @@
 
 //#[macro_use]
 // extern crate mysql;
+mod acme_challenge;
+mod acme_client;
+mod acme_tls_alpn;
+mod admin;
 mod cache_test;
+mod cert_expiry;
+mod client_auth;
+mod client_hello;
 mod connection_source;
+mod forward_resolver;
+mod forward_target;
+mod http_client;
 mod http_parser;
 mod load_single_cert;
+mod ocsp;
+mod plugin_loader;
+mod plugin_resolver;
+mod proxy_protocol;
+mod routing;
+mod session_resumption;
+mod tls_policy;
+mod upstream_tls;
 #[macro_use]
 mod macros;
 //mod cert_database;
@@ -32,17 +50,44 @@ mod env_logger;
 
 //use cert_database::{get_all_certificates, MariaSNIResolver};
 
+use crate::acme_challenge::AcmeChallenges;
+use crate::acme_client::{missing_certificates, start_acme, AcmeClient, ChallengeType};
+use crate::acme_tls_alpn::{AcmeTlsAlpnResolver, TlsAlpnChallenges, ACME_TLS_ALPN_PROTOCOL};
+use crate::admin::{start_admin, SharedExpiry};
+use crate::cert_expiry::{ExpiryMonitor, EXPIRY_CHECK_INTERVAL};
+use crate::client_auth::ClientAuthConfigs;
 use crate::connection_source::ConnectionSource;
+use crate::forward_resolver::{ForwardResolver, ResolvedForwards};
 use crate::load_single_cert::{load_certs, load_private_key};
-
-use std::{cell::RefCell, collections::HashMap, error::Error, io, sync::Arc};
-
-use mio::{net::TcpListener, Events, Interest, Poll, Token};
+use crate::ocsp::{start_ocsp, OcspCache, OcspStapler};
+use crate::plugin_loader::{
+    load_cacher, load_certificate_handler, CertificateHandlerPlugin, SharedCacher,
+};
+use crate::plugin_resolver::PluginResolver;
+use crate::routing::RoutingTable;
+use crate::session_resumption::configure_resumption;
+use crate::tls_policy::TlsPolicy;
+use crate::upstream_tls::UpstreamTlsConfigs;
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    io,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use mio::{net::TcpListener, Events, Interest, Poll, Token, Waker};
 
 use rustls::{self, NoClientAuth};
+use signal_hook::consts::SIGHUP;
+use signal_hook_mio::v0_7::Signals;
 
 const HTTPS_SERVER: Token = Token(0);
 const HTTP_SERVER: Token = Token(1);
+const RELOAD_SIGNAL: Token = Token(2);
+//The ACME client and the forward resolver share the one Waker mio allows.
+const WOKEN: Token = Token(3);
 
 #[macro_use]
 extern crate log;
@@ -53,7 +98,6 @@ extern crate simplelog;
 
 use dotenv;
 use env_logger::activate_env_logger;
-use interfaces::CertificateHandler;
 
 fn main() -> Result<(), Box<dyn Error>> {
     activate_env_logger();
@@ -64,25 +108,21 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 #[allow(dead_code)]
 fn run() -> Result<(), Box<dyn Error>> {
-    let lib = match libloading::Library::new(
-        dotenv::var("SNI_CERT_AND_FORWARDING_PLUGIN").unwrap_or(String::from("")),
-    ) {
-        Ok(a) => Some(a),
+    //A plugin built against another ABI is refused here, better than undefined behaviour.
+    let mut certificate_plugin = match load_certificate_handler() {
+        Ok(plugin) => plugin,
         Err(e) => {
-            info!(target: "0","No certificate plugin to load! Error: \r\n {:?}",e);
-            debug!(target: "0","Error: \r\n {:?}",e);
-            None
+            error!(target: "0","{}",e);
+            return Err(e.into());
         }
     };
-    let ch: Option<Box<dyn CertificateHandler>> = if lib.is_some() {
-        let get_certificate_handler: libloading::Symbol<fn() -> Box<dyn CertificateHandler>> =
-            unsafe { lib.as_ref().unwrap().get(b"get_certificate_handler") }.expect("load symbol");
-        let ch = get_certificate_handler();
-        Some(ch)
-    //Create an arc of the forwards
-    } else {
-        None
-    };
+    let ch = certificate_plugin.as_ref();
+    if let Some(ch) = ch {
+        log_load_summary(ch);
+    }
+
+    //Loaded once, all connections share the same cacher.
+    let cacher: Option<SharedCacher> = load_cacher();
 
     trace!(target: "0","Poll creating new");
     let mut poll = Poll::new()?;
@@ -94,17 +134,73 @@ fn run() -> Result<(), Box<dyn Error>> {
     let mut connections: HashMap<Token, RefCell<ConnectionSource>> = HashMap::new();
     let mut forward_connections: HashMap<Token, RefCell<Token>> = HashMap::new();
 
-    //Create an arc of the forwards
-    let forwards: Box<Arc<HashMap<String, String>>> = if ch.is_some() {
-        ch.as_ref().unwrap().get_forwards().clone()
-    } else {
-        Box::new(Arc::new(HashMap::new()))
-    };
+    //Resolved here as nothing is running yet, after this on the forward resolver thread.
+    //Without a plugin DEFAULT_FORWARD is still looked up.
+    //The certificates are parsed here for the same reason.
+    let plugin_resolver = Arc::new(PluginResolver::new());
+    let initial = ch
+        .map(|ch| Ok::<_, String>((ch.get_forwards()?, ch.certificates()?, ch.generation()?)))
+        .transpose()
+        .map_err(|e| format!("Certificate plugin failed on startup: {}", e))?;
+    let (initial_forwards, certificates, generation) = initial.unwrap_or_default();
+    plugin_resolver.update(&certificates);
+    let table = RoutingTable::new(&initial_forwards);
+    let resolved = ResolvedForwards::resolve(table, &UpstreamTlsConfigs::default());
+    let mut forwards: Arc<RoutingTable> = Arc::new(resolved.table);
+    //ClientConfigs for https:// forwards, they go with the table.
+    let mut upstream_tls: Arc<UpstreamTlsConfigs> = Arc::new(resolved.upstream_tls);
+    let waker = Arc::new(Waker::new(poll.registry(), WOKEN)?);
+    let forward_resolver = ForwardResolver::start(waker.clone(), plugin_resolver.clone());
+    //The plugin bumps this when it has new forwards or certificates, we check it on every
+    //accept.
+    let mut forwards_generation: u64 = generation;
 
     //let forwards: Arc<&mut HashMap<String, String>> = if Arc::new(forwards);// Arc::from(forwards);
 
-    debug!(target: "0","Crating unique Token with first number of 2, 0=HTTPS_SERVER 1=HTTP_SERVER");
-    let mut unique_token = Token(2);
+    //Rebuilt from config and the plugin's policies when either has changed.
+    let mut client_auth: Arc<ClientAuthConfigs> = Arc::new(ClientAuthConfigs::default());
+    let mut client_auth_generation: Option<u64> = None;
+
+    //Expiry is checked every EXPIRY_CHECK_INTERVAL and when the plugin has new certificates.
+    let mut expiry_monitor = ExpiryMonitor::new();
+    let mut expiry_generation: Option<u64> = None;
+    let expiry: SharedExpiry = Arc::new(Mutex::new(Vec::new()));
+    start_admin(expiry.clone())?;
+
+    //The ACME client wakes us through WOKEN when it has a new certificate.
+    let acme_challenges: AcmeChallenges = Arc::new(RwLock::new(HashMap::new()));
+    let tls_alpn_challenges: TlsAlpnChallenges = Arc::new(RwLock::new(HashMap::new()));
+    let acme = match start_acme(
+        acme_challenges.clone(),
+        tls_alpn_challenges.clone(),
+        waker,
+    ) {
+        Ok(acme) => acme,
+        Err(e) => {
+            error!(target: "0","Unable to start the ACME client: {}",e);
+            return Err(e.into());
+        }
+    };
+
+    let ocsp = match start_ocsp() {
+        Ok(ocsp) => ocsp,
+        Err(e) => {
+            error!(target: "0","Unable to start OCSP stapling: {}",e);
+            return Err(e.into());
+        }
+    };
+
+    let tls_policy = match TlsPolicy::from_env() {
+        Ok(tls_policy) => tls_policy,
+        Err(e) => {
+            error!(target: "0","Bad TLS policy: {}",e);
+            return Err(e.into());
+        }
+    };
+    info!(target: "0","TLS policy {}",tls_policy);
+
+    debug!(target: "0","Crating unique Token with first number of 4, 0=HTTPS_SERVER 1=HTTP_SERVER 2=RELOAD_SIGNAL 3=WOKEN");
+    let mut unique_token = Token(4);
 
     let mut http_bind = String::from("0.0.0.0:80");
     if dotenv::var("HTTP").is_ok() {
@@ -129,6 +225,12 @@ fn run() -> Result<(), Box<dyn Error>> {
     poll.registry()
         .register(&mut http_server, HTTP_SERVER, Interest::READABLE)?;
 
+    //kill -HUP reloads certificates and forwards from the plugin, for new connections.
+    trace!(target: "0","Adding SIGHUP to polling");
+    let mut signals = Signals::new([SIGHUP])?;
+    poll.registry()
+        .register(&mut signals, RELOAD_SIGNAL, Interest::READABLE)?;
+
     trace!(target: "0","Creating tls config");
     let mut config = rustls::ServerConfig::new(NoClientAuth::new());
     let do_single_cert_as_default: bool = dotenv::var("DO_SINGLE_CERT_AS_DEFAULT")
@@ -142,8 +244,7 @@ fn run() -> Result<(), Box<dyn Error>> {
 
     if !do_single_cert_as_default {
         trace!(target: "0","Loading resolver");
-        let resolver = ch.unwrap().get_sni_resolver().as_ref().clone();
-        config.cert_resolver = resolver;
+        config.cert_resolver = plugin_resolver.clone();
     } else {
         //TODO: Single cert in config
         trace!(target: "0","Load certificate for single cert server");
@@ -165,43 +266,129 @@ fn run() -> Result<(), Box<dyn Error>> {
             .unwrap();
     }
 
-    trace!(target: "0","Adding protocolls to tls config http/https(1.1,1.2)");
-    config.set_protocols(&[b"http/1.2".to_vec(), b"http/1.1".to_vec()]);
+    config.cert_resolver =
+        wrap_resolver(config.cert_resolver.clone(), &ocsp, &tls_alpn_challenges);
+
+    trace!(target: "0","Adding TLS versions, cipher suites and protocols to tls config");
+    tls_policy.apply(&mut config);
+    //Last, so it is only picked by a CA that offers nothing else.
+    if acme.as_ref().map(|acme| acme.challenge_type) == Some(ChallengeType::TlsAlpn01) {
+        config.alpn_protocols.push(ACME_TLS_ALPN_PROTOCOL.to_vec());
+    }
+    if let Err(e) = configure_resumption(&mut config) {
+        error!(target: "0","Unable to set up TLS session resumption: {}",e);
+        return Err(e.into());
+    }
+    //Every connection shares it, and with it the session cache and ticket keys.
+    let server_config: Arc<rustls::ServerConfig> = Arc::new(config.clone());
 
     info!(target: "0","Spinning up servers");
     loop {
         //info!(target: "0","Polling");
-        poll.poll(&mut events, None)?;
+        //Also after a reload or when the plugin has picked up new certificates.
+        if expiry_monitor.is_due() || expiry_generation != Some(forwards_generation) {
+            check_expiry(&certificate_plugin, &mut expiry_monitor, &expiry);
+            expiry_generation = Some(forwards_generation);
+            if let Some(acme) = acme.as_ref() {
+                request_missing_certificates(acme, &forwards, &expiry);
+            }
+        }
+        //Wakes up at least every EXPIRY_CHECK_INTERVAL to look at certificate expiry.
+        match poll.poll(&mut events, Some(EXPIRY_CHECK_INTERVAL)) {
+            Ok(()) => (),
+            //A signal like SIGHUP interrupts the poll, we pick it up through RELOAD_SIGNAL.
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
         //poll.poll(&mut events, Some(Duration::from_millis(500)))?;
 
         for event in events.iter() {
             match event.token() {
                 HTTP_SERVER => {
+                    refresh_forwards(
+                        &certificate_plugin,
+                        &forward_resolver,
+                        &upstream_tls,
+                        &mut forwards_generation,
+                    );
+                    //Plain HTTP needs the policies too, to refuse the hosts that have one.
+                    if client_auth_generation != Some(forwards_generation) {
+                        client_auth = client_auth_configs(&certificate_plugin, &config);
+                        client_auth_generation = Some(forwards_generation);
+                    }
                     do_server_accept(
                         "HTTP",
                         &mut http_server,
                         &mut unique_token,
                         &mut connections,
                         &mut forward_connections,
-                        &mut forwards.as_ref(),
+                        &forwards,
+                        &cacher,
+                        &acme_challenges,
+                        &client_auth,
+                        &upstream_tls,
                         &mut poll,
-                        &mut config,
+                        &server_config,
                         false,
                     );
                 }
                 HTTPS_SERVER => {
+                    refresh_forwards(
+                        &certificate_plugin,
+                        &forward_resolver,
+                        &upstream_tls,
+                        &mut forwards_generation,
+                    );
+                    if client_auth_generation != Some(forwards_generation) {
+                        client_auth = client_auth_configs(&certificate_plugin, &config);
+                        client_auth_generation = Some(forwards_generation);
+                    }
                     do_server_accept(
                         "HTTPS",
                         &mut https_server,
                         &mut unique_token,
                         &mut connections,
                         &mut forward_connections,
-                        &mut forwards.as_ref(),
+                        &forwards,
+                        &cacher,
+                        &acme_challenges,
+                        &client_auth,
+                        &upstream_tls,
                         &mut poll,
-                        &mut config,
+                        &server_config,
                         true,
                     );
                 }
+                RELOAD_SIGNAL => {
+                    //Several signals before we get here is still just one reload.
+                    if signals.pending().count() > 0 {
+                        reload_certificates(
+                            &mut certificate_plugin,
+                            &forward_resolver,
+                            &mut forwards_generation,
+                        );
+                        //Client certificate files are read again too.
+                        client_auth_generation = None;
+                    }
+                }
+                WOKEN => {
+                    if let Some(acme) = acme.as_ref() {
+                        store_issued_certificates(&mut certificate_plugin, acme);
+                        refresh_forwards(
+                            &certificate_plugin,
+                            &forward_resolver,
+                            &upstream_tls,
+                            &mut forwards_generation,
+                        );
+                    }
+                    for resolved in forward_resolver.resolved() {
+                        forwards = Arc::new(resolved.table);
+                        upstream_tls = Arc::new(resolved.upstream_tls);
+                        info!(target: "0","Now using {} forwards",forwards.len());
+                        //Hosts new in the table may need a certificate.
+                        expiry_generation = None;
+                    }
+                }
                 token => {
                     //Too much logging  trace!(target: "0","New token action: {:?}", event);
                     let server_token =
@@ -237,15 +424,222 @@ fn run() -> Result<(), Box<dyn Error>> {
     }
 }
 
+// Asks the certificate plugin for fresh certificates and forwards. Only new connections
+// get them, the ones already running keep their own copy of the config and forwards. If
+// the plugin fails we keep serving what we had. The forwards are put to use once the
+// forward resolver is done with them, with CA and client certificate files read again.
+fn reload_certificates(
+    certificate_plugin: &mut Option<CertificateHandlerPlugin>,
+    forward_resolver: &ForwardResolver,
+    forwards_generation: &mut u64,
+) {
+    let plugin = match certificate_plugin.as_mut() {
+        Some(plugin) => plugin,
+        None => {
+            info!(target: "0","SIGHUP received but there is no certificate plugin to reload");
+            return;
+        }
+    };
+    info!(target: "0","SIGHUP received, reloading certificates and forwards");
+    let reloaded = plugin.reload().and_then(|()| {
+        Ok((
+            plugin.get_forwards()?,
+            plugin.certificates()?,
+            plugin.generation()?,
+        ))
+    });
+    match reloaded {
+        Ok((new_forwards, certificates, generation)) => {
+            let table = RoutingTable::new(&new_forwards);
+            info!(target: "0","Reload done, {} forwards",table.len());
+            forward_resolver.resolve(
+                table,
+                Arc::new(UpstreamTlsConfigs::default()),
+                Some(certificates),
+            );
+            *forwards_generation = generation;
+            log_load_summary(plugin);
+        }
+        Err(e) => {
+            error!(target: "0","Reload failed, keeping current certificates and forwards: {}",e);
+        }
+    }
+}
+
+// What we put around the resolver from the plugin: OCSP responses are stapled to its
+// certificates, and acme-tls/1 handshakes get the challenge certificate instead.
+fn wrap_resolver(
+    resolver: Arc<dyn rustls::ResolvesServerCert>,
+    ocsp: &Option<Arc<OcspCache>>,
+    tls_alpn_challenges: &TlsAlpnChallenges,
+) -> Arc<dyn rustls::ResolvesServerCert> {
+    let resolver: Arc<dyn rustls::ResolvesServerCert> = match ocsp {
+        Some(ocsp) => Arc::new(OcspStapler::new(resolver, ocsp.clone())),
+        None => resolver,
+    };
+    Arc::new(AcmeTlsAlpnResolver::new(resolver, tls_alpn_challenges.clone()))
+}
+
+// Picks up forwards the plugin has found on its own since last time, e.g. new rows in the
+// database. Like a reload, only new connections see them, once they are resolved.
+fn refresh_forwards(
+    certificate_plugin: &Option<CertificateHandlerPlugin>,
+    forward_resolver: &ForwardResolver,
+    upstream_tls: &Arc<UpstreamTlsConfigs>,
+    forwards_generation: &mut u64,
+) {
+    let plugin = match certificate_plugin.as_ref() {
+        Some(plugin) => plugin,
+        None => return,
+    };
+    let changed = plugin.generation().and_then(|generation| {
+        if generation == *forwards_generation {
+            return Ok(None);
+        }
+        Ok(Some((
+            plugin.get_forwards()?,
+            plugin.certificates()?,
+            generation,
+        )))
+    });
+    match changed {
+        Ok(Some((new_forwards, certificates, generation))) => {
+            let table = RoutingTable::new(&new_forwards);
+            info!(target: "0","Certificate plugin has new forwards, {} forwards",table.len());
+            forward_resolver.resolve(table, upstream_tls.clone(), Some(certificates));
+            *forwards_generation = generation;
+            log_load_summary(plugin);
+        }
+        Ok(None) => (),
+        Err(e) => {
+            error!(target: "0","Unable to get new forwards from the certificate plugin: {}",e);
+        }
+    }
+}
+
+// Warns about certificates close to expiry and updates what the admin listener shows.
+fn check_expiry(
+    certificate_plugin: &Option<CertificateHandlerPlugin>,
+    expiry_monitor: &mut ExpiryMonitor,
+    expiry: &SharedExpiry,
+) {
+    let plugin = match certificate_plugin.as_ref() {
+        Some(plugin) => plugin,
+        None => return,
+    };
+    match plugin.certificate_expiry() {
+        Ok(certificates) => {
+            expiry_monitor.check(&certificates);
+            if let Ok(mut expiry) = expiry.lock() {
+                *expiry = certificates;
+            }
+        }
+        Err(e) => {
+            error!(target: "0","Certificate plugin failed listing certificate expiry: {}",e);
+        }
+    }
+}
+
+// ServerConfigs for the hosts the plugin has a client certificate policy for, made from
+// config so they only differ in asking for a client certificate.
+fn client_auth_configs(
+    certificate_plugin: &Option<CertificateHandlerPlugin>,
+    config: &rustls::ServerConfig,
+) -> Arc<ClientAuthConfigs> {
+    let plugin = match certificate_plugin.as_ref() {
+        Some(plugin) => plugin,
+        None => return Arc::new(ClientAuthConfigs::default()),
+    };
+    match plugin.client_auth() {
+        Ok(policies) => {
+            if !policies.is_empty() {
+                info!(target: "0","{} hosts have a client certificate policy",policies.len());
+            }
+            Arc::new(ClientAuthConfigs::new(config, &policies))
+        }
+        Err(e) => {
+            error!(target: "0","Certificate plugin failed listing client certificate policies: {}",e);
+            Arc::new(ClientAuthConfigs::default())
+        }
+    }
+}
+
+// Asks the ACME client for certificates for the hosts we forward that have none, or one
+// that is about to expire.
+fn request_missing_certificates(
+    acme: &AcmeClient,
+    forwards: &Arc<RoutingTable>,
+    expiry: &SharedExpiry,
+) {
+    let missing = match expiry.lock() {
+        Ok(expiry) => missing_certificates(forwards, &expiry, acme.renew_before),
+        Err(_) => return,
+    };
+    if !missing.is_empty() {
+        info!(target: "0","{} hosts need a certificate from ACME",missing.len());
+        acme.request_certificates(missing);
+    }
+}
+
+// Hands what the ACME client issued to the certificate plugin, which serves and keeps it.
+fn store_issued_certificates(
+    certificate_plugin: &mut Option<CertificateHandlerPlugin>,
+    acme: &AcmeClient,
+) {
+    for issued in acme.issued() {
+        let plugin = match certificate_plugin.as_mut() {
+            Some(plugin) => plugin,
+            None => {
+                error!(target: "0","No certificate plugin to store the certificate for {}",issued.names.join(", "));
+                continue;
+            }
+        };
+        let stored = plugin.store_certificate(
+            &issued.names,
+            &issued.forward,
+            &issued.fullchain,
+            &issued.privkey,
+        );
+        match stored {
+            Ok(()) => {
+                info!(target: "0","Stored new certificate for {}",issued.names.join(", "));
+            }
+            Err(e) => {
+                error!(target: "0","Unable to store the certificate for {}: {}",issued.names.join(", "),e);
+            }
+        }
+    }
+}
+
+// Bad certificates are skipped by the plugins, make sure it is seen.
+fn log_load_summary(plugin: &CertificateHandlerPlugin) {
+    let summary = match plugin.load_summary() {
+        Ok(summary) => summary,
+        Err(e) => {
+            error!(target: "0","Certificate plugin failed summing up its certificates: {}",e);
+            return;
+        }
+    };
+    if summary.skipped > 0 || summary.expired > 0 {
+        warn!(target: "0","Certificates: {} loaded, {} skipped as invalid, {} expired",summary.loaded,summary.skipped,summary.expired);
+    } else {
+        info!(target: "0","Certificates: {} loaded",summary.loaded);
+    }
+}
+
 fn do_server_accept(
     https_or_http: &str,
     server: &mut TcpListener,
     unique_token: &mut Token,
     connections: &mut HashMap<Token, RefCell<ConnectionSource>>,
     forward_connections: &mut HashMap<Token, RefCell<Token>>,
-    forwards: &Arc<HashMap<String, String>>,
+    forwards: &Arc<RoutingTable>,
+    cacher: &Option<SharedCacher>,
+    acme_challenges: &AcmeChallenges,
+    client_auth: &Arc<ClientAuthConfigs>,
+    upstream_tls: &Arc<UpstreamTlsConfigs>,
     poll: &mut Poll,
-    config: &mut rustls::ServerConfig,
+    server_config: &Arc<rustls::ServerConfig>,
     tls: bool,
 ) {
     trace!(target: "0","Connection to {} server", https_or_http);
@@ -276,8 +670,15 @@ fn do_server_accept(
             server_token.0
         );
 
+        //HTTP_PROXY_PROTOCOL/HTTPS_PROXY_PROTOCOL, when we sit behind a TCP load balancer
+        //that sends a PROXY header with the real client address.
+        let expect_proxy_header: bool = dotenv::var(format!("{}_PROXY_PROTOCOL", https_or_http))
+            .unwrap_or(String::from("false"))
+            .parse()
+            .unwrap_or(false);
+
         let tls_session: Option<rustls::ServerSession> = if tls {
-            Some(rustls::ServerSession::new(&Arc::new(config.clone())))
+            Some(rustls::ServerSession::new(server_config))
         } else {
             None
         };
@@ -288,6 +689,11 @@ fn do_server_accept(
             forward_token,
             tls_session,
             Arc::clone(forwards),
+            cacher.clone(),
+            acme_challenges.clone(),
+            Arc::clone(client_auth),
+            Arc::clone(upstream_tls),
+            expect_proxy_header,
         );
 
         trace!(
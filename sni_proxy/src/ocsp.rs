@@ -0,0 +1,796 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use chrono::NaiveDateTime;
+use interfaces::unix_now;
+use ring::digest;
+use rustls::{sign, ClientHello, ResolvesServerCert};
+use x509_parser::{
+    extensions::{GeneralName, ParsedExtension},
+    objects::{OID_ACCESSDESCRIPTOR_OCSP, OID_EXT_AUTHORITYINFOACCESS},
+};
+
+use crate::http_client::{HttpClient, DEFAULT_CA_FILE};
+
+const DEFAULT_CACHE_DIR: &str = "ocsp_cache";
+//Between tries after a failed fetch, what we had is stapled until its nextUpdate.
+const RETRY_SECONDS: i64 = 5 * 60;
+//Never fetch more often than this, whatever the responder says.
+const MIN_REFRESH_SECONDS: i64 = 60;
+//Responses without nextUpdate are fetched again after an hour and stapled for a day.
+const NO_NEXT_UPDATE_REFRESH: i64 = 60 * 60;
+const NO_NEXT_UPDATE_LIFETIME: i64 = 24 * 60 * 60;
+//Responders whose clock is a bit ahead of ours.
+const CLOCK_SKEW: i64 = 5 * 60;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_CONTEXT_0: u8 = 0xa0;
+const CERT_STATUS_GOOD: u8 = 0x80;
+const CERT_STATUS_REVOKED: u8 = 0xa1;
+//1.3.14.3.2.26, what every responder understands in a CertID.
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+//1.3.6.1.5.5.7.48.1.1
+const OID_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+//The signature algorithms responders use, 1.2.840.10045.4.3.x, 1.2.840.113549.1.1.x and
+//1.3.101.112.
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_ECDSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const OID_RSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const OID_RSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const OID_RSA_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+// What the responder says about a certificate, times in seconds since the epoch.
+#[derive(Debug, PartialEq)]
+enum CertStatus {
+    Good {
+        this_update: i64,
+        next_update: Option<i64>,
+    },
+    Revoked,
+    Unknown,
+}
+
+// A response we staple, until next_update (seconds since the epoch).
+struct Stapled {
+    response: Vec<u8>,
+    next_update: i64,
+}
+
+// OCSP responses by the SHA-256 (hex) of the certificate they are for. A certificate is
+// in here from its first handshake on, with None until there is a good response.
+pub struct OcspCache {
+    responses: RwLock<HashMap<String, Option<Stapled>>>,
+    new_certificates: Mutex<Sender<(String, OcspCertificate)>>,
+    cache_dir: PathBuf,
+}
+
+impl OcspCache {
+    // The response to staple for this chain. Chains we have not seen before start with the
+    // response on disk if it is still good and are handed to the fetcher, that is the
+    // only time a handshake waits on the disk.
+    fn response_for(&self, chain: &[rustls::Certificate]) -> Option<Vec<u8>> {
+        let fingerprint = fingerprint(&chain.first()?.0);
+        if let Some(known) = self.responses.read().ok()?.get(&fingerprint) {
+            return known
+                .as_ref()
+                .filter(|stapled| stapled.next_update > unix_now())
+                .map(|stapled| stapled.response.clone());
+        }
+        let mut responses = self.responses.write().ok()?;
+        if responses.contains_key(&fingerprint) {
+            return None;
+        }
+        let mut certificate = match ocsp_certificate(chain) {
+            Ok(certificate) => certificate,
+            Err(e) => {
+                debug!(target: "0","No OCSP stapling for certificate {}: {}",fingerprint,e);
+                responses.insert(fingerprint, None);
+                return None;
+            }
+        };
+        let stapled = fs::read(self.cache_file(&fingerprint))
+            .ok()
+            .and_then(|response| {
+                let (this_update, next_update) = match check_response(
+                    &response,
+                    &certificate.serial,
+                    &certificate.issuer,
+                    unix_now(),
+                ) {
+                    Ok(CertStatus::Good {
+                        this_update,
+                        next_update,
+                    }) => (this_update, next_update),
+                    _ => return None,
+                };
+                debug!(target: "0","OCSP response for {} loaded from the cache",certificate.name);
+                certificate.refresh_at = refresh_time(this_update, next_update);
+                Some(Stapled {
+                    response,
+                    next_update: stapled_until(this_update, next_update),
+                })
+            });
+        let response = stapled.as_ref().map(|stapled| stapled.response.clone());
+        responses.insert(fingerprint.clone(), stapled);
+        if let Ok(new_certificates) = self.new_certificates.lock() {
+            let _ = new_certificates.send((fingerprint, certificate));
+        }
+        response
+    }
+
+    fn publish(&self, fingerprint: &str, stapled: Option<Stapled>) {
+        if let Ok(mut responses) = self.responses.write() {
+            responses.insert(String::from(fingerprint), stapled);
+        }
+    }
+
+    fn cache_file(&self, fingerprint: &str) -> PathBuf {
+        self.cache_dir.join(format!("{}.der", fingerprint))
+    }
+}
+
+// Wraps the resolver from the certificate plugin and staples the OCSP response for the
+// certificate it picked, when we have a current one.
+pub struct OcspStapler {
+    inner: Arc<dyn ResolvesServerCert>,
+    cache: Arc<OcspCache>,
+}
+
+impl OcspStapler {
+    pub fn new(inner: Arc<dyn ResolvesServerCert>, cache: Arc<OcspCache>) -> OcspStapler {
+        OcspStapler { inner, cache }
+    }
+}
+
+impl ResolvesServerCert for OcspStapler {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+        let mut certified_key = self.inner.resolve(client_hello)?;
+        if certified_key.ocsp.is_none() {
+            certified_key.ocsp = self.cache.response_for(&certified_key.cert);
+        }
+        Some(certified_key)
+    }
+}
+
+// Starts OCSP stapling if OCSP_STAPLING=true. The responder is taken from the AIA extension
+// of each certificate, which needs its issuer next in the chain. Responses are fetched in
+// a thread, kept in OCSP_CACHE_DIR so a restart staples right away, and fetched again
+// halfway to their nextUpdate.
+//
+//   OCSP_CACHE_DIR  Where responses are kept, one file per certificate.
+//   OCSP_CA_FILE    CAs trusted for responders on https://, most are plain http.
+pub fn start_ocsp() -> Result<Option<Arc<OcspCache>>, String> {
+    let enabled: bool = dotenv::var("OCSP_STAPLING")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(false);
+    if !enabled {
+        return Ok(None);
+    }
+    let cache_dir = PathBuf::from(
+        dotenv::var("OCSP_CACHE_DIR").unwrap_or_else(|_| String::from(DEFAULT_CACHE_DIR)),
+    );
+    fs::create_dir_all(&cache_dir)
+        .map_err(|e| format!("Unable to create {}: {}", cache_dir.display(), e))?;
+    let ca_file = dotenv::var("OCSP_CA_FILE").unwrap_or_else(|_| String::from(DEFAULT_CA_FILE));
+    let http = HttpClient::new(&ca_file)?;
+
+    let (new_tx, new_rx) = channel();
+    let cache = Arc::new(OcspCache {
+        responses: RwLock::new(HashMap::new()),
+        new_certificates: Mutex::new(new_tx),
+        cache_dir,
+    });
+    info!(target: "0","Starting OCSP stapling, responses are kept in {}",cache.cache_dir.display());
+    let fetcher = OcspFetcher {
+        cache: cache.clone(),
+        http,
+        certificates: HashMap::new(),
+    };
+    thread::spawn(move || fetcher.run(new_rx));
+    Ok(Some(cache))
+}
+
+// A certificate we can get OCSP responses for.
+struct OcspCertificate {
+    name: String,
+    url: String,
+    request: Vec<u8>,
+    serial: Vec<u8>,
+    //The issuer certificate, responses must be signed by it or a responder it certified.
+    issuer: Vec<u8>,
+    //Seconds since the epoch.
+    refresh_at: i64,
+}
+
+struct OcspFetcher {
+    cache: Arc<OcspCache>,
+    http: HttpClient,
+    certificates: HashMap<String, OcspCertificate>,
+}
+
+impl OcspFetcher {
+    fn run(mut self, new_certificates: Receiver<(String, OcspCertificate)>) {
+        loop {
+            let now = unix_now();
+            let next = self
+                .certificates
+                .values()
+                .map(|certificate| certificate.refresh_at)
+                .min()
+                .unwrap_or(now + NO_NEXT_UPDATE_REFRESH);
+            let wait = Duration::from_secs((next - now).max(0) as u64);
+            match new_certificates.recv_timeout(wait) {
+                Ok((fingerprint, certificate)) => {
+                    self.certificates.insert(fingerprint, certificate);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            let now = unix_now();
+            let due: Vec<String> = self
+                .certificates
+                .iter()
+                .filter(|(_, certificate)| certificate.refresh_at <= now)
+                .map(|(fingerprint, _)| fingerprint.clone())
+                .collect();
+            for fingerprint in due {
+                self.fetch(&fingerprint);
+            }
+        }
+    }
+
+    fn fetch(&mut self, fingerprint: &str) {
+        let cache_file = self.cache.cache_file(fingerprint);
+        let certificate = match self.certificates.get_mut(fingerprint) {
+            Some(certificate) => certificate,
+            None => return,
+        };
+        let fetched = self
+            .http
+            .post(
+                &certificate.url,
+                "application/ocsp-request",
+                &certificate.request,
+            )
+            .and_then(|response| {
+                if response.is_success() {
+                    Ok(response.body)
+                } else {
+                    Err(format!("{} answered {}", certificate.url, response.status))
+                }
+            })
+            .and_then(|response| {
+                let status = check_response(
+                    &response,
+                    &certificate.serial,
+                    &certificate.issuer,
+                    unix_now(),
+                )?;
+                Ok((response, status))
+            });
+        match fetched {
+            Ok((
+                response,
+                CertStatus::Good {
+                    this_update,
+                    next_update,
+                },
+            )) => {
+                debug!(target: "0","OCSP response for {} fetched from {}",certificate.name,certificate.url);
+                certificate.refresh_at = refresh_time(this_update, next_update);
+                if let Err(e) = write_file(&cache_file, &response) {
+                    warn!(target: "0","Unable to keep the OCSP response for {} in {}: {}",certificate.name,cache_file.display(),e);
+                }
+                self.cache.publish(
+                    fingerprint,
+                    Some(Stapled {
+                        response,
+                        next_update: stapled_until(this_update, next_update),
+                    }),
+                );
+            }
+            //A revoked certificate is never stapled again, the old response is a lie.
+            Ok((_, CertStatus::Revoked)) => {
+                error!(target: "0","Certificate {} is revoked, no more OCSP stapling for it",certificate.name);
+                certificate.refresh_at = unix_now() + NO_NEXT_UPDATE_REFRESH;
+                self.cache.publish(fingerprint, None);
+                let _ = fs::remove_file(&cache_file);
+            }
+            Ok((_, CertStatus::Unknown)) => {
+                error!(target: "0","Certificate {} is unknown to {}, next try in {} minutes",certificate.name,certificate.url,RETRY_SECONDS / 60);
+                certificate.refresh_at = unix_now() + RETRY_SECONDS;
+            }
+            Err(e) => {
+                error!(target: "0","OCSP fetch for {} failed, next try in {} minutes: {}",certificate.name,RETRY_SECONDS / 60,e);
+                certificate.refresh_at = unix_now() + RETRY_SECONDS;
+            }
+        }
+    }
+}
+
+// The responder, request and serial for the first certificate in chain, its issuer must
+// be the second.
+fn ocsp_certificate(chain: &[rustls::Certificate]) -> Result<OcspCertificate, String> {
+    let (leaf, issuer) = match chain {
+        [leaf, issuer, ..] => (leaf, issuer),
+        _ => return Err(String::from("no issuer in the chain")),
+    };
+    let (_, leaf) = x509_parser::parse_x509_der(&leaf.0)
+        .map_err(|e| format!("unable to parse certificate: {:?}", e))?;
+    let issuer_der = issuer.0.clone();
+    let (_, issuer) = x509_parser::parse_x509_der(&issuer.0)
+        .map_err(|e| format!("unable to parse issuer: {:?}", e))?;
+    let leaf = &leaf.tbs_certificate;
+
+    let url = match leaf
+        .extensions
+        .get(&OID_EXT_AUTHORITYINFOACCESS)
+        .map(|extension| extension.parsed_extension())
+    {
+        Some(ParsedExtension::AuthorityInfoAccess(aia)) => aia
+            .accessdescs
+            .get(&OID_ACCESSDESCRIPTOR_OCSP)
+            .and_then(|names| {
+                names.iter().find_map(|name| match name {
+                    GeneralName::URI(uri) => Some(String::from(*uri)),
+                    _ => None,
+                })
+            }),
+        _ => None,
+    }
+    .ok_or_else(|| String::from("no OCSP responder in the certificate"))?;
+
+    //For the logs, the first DNS name or else the serial.
+    let name = leaf
+        .subject_alternative_name()
+        .and_then(|(_, san)| {
+            san.general_names.iter().find_map(|name| match name {
+                GeneralName::DNSName(dns_name) => Some(String::from(*dns_name)),
+                _ => None,
+            })
+        })
+        .unwrap_or_else(|| leaf.raw_serial_as_string());
+    let serial = leaf.raw_serial().to_vec();
+    let name_hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, leaf.issuer.as_raw());
+    let key_hash = digest::digest(
+        &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        issuer.tbs_certificate.subject_pki.subject_public_key.data,
+    );
+
+    //OCSPRequest { TBSRequest { requestList { Request { CertID } } } }, RFC 6960.
+    let algorithm = der(
+        TAG_SEQUENCE,
+        &[der(TAG_OID, OID_SHA1), der(TAG_NULL, &[])].concat(),
+    );
+    let cert_id = der(
+        TAG_SEQUENCE,
+        &[
+            algorithm,
+            der(TAG_OCTET_STRING, name_hash.as_ref()),
+            der(TAG_OCTET_STRING, key_hash.as_ref()),
+            der(TAG_INTEGER, &serial),
+        ]
+        .concat(),
+    );
+    let request = der(
+        TAG_SEQUENCE,
+        &der(
+            TAG_SEQUENCE,
+            &der(TAG_SEQUENCE, &der(TAG_SEQUENCE, &cert_id)),
+        ),
+    );
+    Ok(OcspCertificate {
+        name,
+        url,
+        request,
+        serial,
+        issuer: issuer_der,
+        refresh_at: unix_now(),
+    })
+}
+
+// What the response says about the certificate with serial. It has to be signed by the
+// issuer, and a good one has to be current at now.
+fn check_response(
+    response: &[u8],
+    serial: &[u8],
+    issuer: &[u8],
+    now: i64,
+) -> Result<CertStatus, String> {
+    let (response, _) = der_expect(response, TAG_SEQUENCE)?;
+    let (status, rest) = der_expect(response, TAG_ENUMERATED)?;
+    if status != [0] {
+        return Err(format!("responder status {:?}", status));
+    }
+    let (bytes, _) = der_expect(rest, TAG_CONTEXT_0)?;
+    let (bytes, _) = der_expect(bytes, TAG_SEQUENCE)?;
+    let (response_type, rest) = der_expect(bytes, TAG_OID)?;
+    if response_type != OID_OCSP_BASIC {
+        return Err(String::from("not a basic OCSP response"));
+    }
+    let (basic, _) = der_expect(rest, TAG_OCTET_STRING)?;
+    let (basic, _) = der_expect(basic, TAG_SEQUENCE)?;
+    let (signed, data, rest) = signed_data(basic)?;
+    //Optional [0] with the certificates of a delegated responder.
+    let certs = match der_element(rest) {
+        Some((TAG_CONTEXT_0, certs, _)) => der_expect(certs, TAG_SEQUENCE)?.0,
+        _ => &[],
+    };
+    verify_response_signature(&signed, certs, issuer, now)?;
+
+    //Optional [0] version, then the responderID and producedAt.
+    let (tag, _, mut rest) = der_element(data).ok_or_else(bad_response)?;
+    if tag == TAG_CONTEXT_0 {
+        rest = der_element(rest).ok_or_else(bad_response)?.2;
+    }
+    let (_, rest) = der_expect(rest, TAG_GENERALIZED_TIME)?;
+    let (mut responses, _) = der_expect(rest, TAG_SEQUENCE)?;
+    while !responses.is_empty() {
+        let (single, rest) = der_expect(responses, TAG_SEQUENCE)?;
+        responses = rest;
+        let (cert_id, rest) = der_expect(single, TAG_SEQUENCE)?;
+        if cert_id_serial(cert_id)? != serial {
+            continue;
+        }
+        let (status, _, rest) = der_element(rest).ok_or_else(bad_response)?;
+        match status {
+            CERT_STATUS_GOOD => {}
+            CERT_STATUS_REVOKED => return Ok(CertStatus::Revoked),
+            _ => return Ok(CertStatus::Unknown),
+        }
+        let (this_update, rest) = der_expect(rest, TAG_GENERALIZED_TIME)?;
+        let this_update = generalized_time(this_update)?;
+        let next_update = match der_element(rest) {
+            Some((TAG_CONTEXT_0, next_update, _)) => Some(generalized_time(
+                der_expect(next_update, TAG_GENERALIZED_TIME)?.0,
+            )?),
+            _ => None,
+        };
+        if this_update > now + CLOCK_SKEW {
+            return Err(String::from("response is from the future"));
+        }
+        if stapled_until(this_update, next_update) <= now {
+            return Err(String::from("response is out of date"));
+        }
+        return Ok(CertStatus::Good {
+            this_update,
+            next_update,
+        });
+    }
+    Err(String::from("no response for the certificate"))
+}
+
+// The signed part of a certificate or basic OCSP response, with its algorithm and
+// signature.
+struct SignedData<'a> {
+    data: &'a [u8],
+    algorithm: &'a [u8],
+    signature: &'a [u8],
+}
+
+// SEQUENCE { data, AlgorithmIdentifier, BIT STRING } from the contents of a certificate
+// or basic response, also gives the contents of data and what follows the signature.
+fn signed_data(input: &[u8]) -> Result<(SignedData<'_>, &[u8], &[u8]), String> {
+    let (contents, rest) = der_expect(input, TAG_SEQUENCE)?;
+    let data = &input[..input.len() - rest.len()];
+    let (algorithm, rest) = der_expect(rest, TAG_SEQUENCE)?;
+    let (algorithm, _) = der_expect(algorithm, TAG_OID)?;
+    let (signature, rest) = der_expect(rest, TAG_BIT_STRING)?;
+    let signature = match signature.split_first() {
+        Some((0, signature)) => signature,
+        _ => return Err(bad_response()),
+    };
+    Ok((
+        SignedData {
+            data,
+            algorithm,
+            signature,
+        },
+        contents,
+        rest,
+    ))
+}
+
+// RFC 6960 section 4.2.2.2, the issuer signs its responses itself or certifies a
+// responder for it with the OCSPSigning extended key usage.
+fn verify_response_signature(
+    signed: &SignedData,
+    mut certs: &[u8],
+    issuer: &[u8],
+    now: i64,
+) -> Result<(), String> {
+    if verify_signed_by(issuer, signed).is_ok() {
+        return Ok(());
+    }
+    while !certs.is_empty() {
+        let (_, _, rest) = der_element(certs).ok_or_else(bad_response)?;
+        let cert = &certs[..certs.len() - rest.len()];
+        certs = rest;
+        if is_delegated_responder(cert, issuer, now) && verify_signed_by(cert, signed).is_ok() {
+            return Ok(());
+        }
+    }
+    Err(String::from(
+        "response is not signed by the issuer or its responder",
+    ))
+}
+
+fn is_delegated_responder(cert: &[u8], issuer: &[u8], now: i64) -> bool {
+    let signed =
+        match der_expect(cert, TAG_SEQUENCE).and_then(|(contents, _)| signed_data(contents)) {
+            Ok((signed, _, _)) => signed,
+            Err(_) => return false,
+        };
+    if verify_signed_by(issuer, &signed).is_err() {
+        return false;
+    }
+    match x509_parser::parse_x509_der(cert) {
+        Ok((_, cert)) => {
+            let validity = cert.validity();
+            validity.not_before.timestamp() <= now
+                && now <= validity.not_after.timestamp()
+                && cert
+                    .tbs_certificate
+                    .extended_key_usage()
+                    .map(|(_, eku)| eku.ocscp_signing)
+                    .unwrap_or(false)
+        }
+        Err(_) => false,
+    }
+}
+
+fn verify_signed_by(cert: &[u8], signed: &SignedData) -> Result<(), String> {
+    let cert = webpki::EndEntityCert::from(cert)
+        .map_err(|e| format!("unable to parse the signer: {:?}", e))?;
+    //An ECDSA OID only names the hash, the curve is the one of the signer's key.
+    let algorithms: &[&webpki::SignatureAlgorithm] = match signed.algorithm {
+        OID_ECDSA_SHA256 => &[&webpki::ECDSA_P256_SHA256, &webpki::ECDSA_P384_SHA256],
+        OID_ECDSA_SHA384 => &[&webpki::ECDSA_P384_SHA384, &webpki::ECDSA_P256_SHA384],
+        OID_RSA_SHA256 => &[&webpki::RSA_PKCS1_2048_8192_SHA256],
+        OID_RSA_SHA384 => &[&webpki::RSA_PKCS1_2048_8192_SHA384],
+        OID_RSA_SHA512 => &[&webpki::RSA_PKCS1_2048_8192_SHA512],
+        OID_ED25519 => &[&webpki::ED25519],
+        _ => return Err(String::from("unsupported signature algorithm")),
+    };
+    if algorithms.iter().any(|algorithm| {
+        cert.verify_signature(algorithm, signed.data, signed.signature)
+            .is_ok()
+    }) {
+        Ok(())
+    } else {
+        Err(String::from("bad signature"))
+    }
+}
+
+fn cert_id_serial(cert_id: &[u8]) -> Result<&[u8], String> {
+    let (_, rest) = der_expect(cert_id, TAG_SEQUENCE)?;
+    let (_, rest) = der_expect(rest, TAG_OCTET_STRING)?;
+    let (_, rest) = der_expect(rest, TAG_OCTET_STRING)?;
+    Ok(der_expect(rest, TAG_INTEGER)?.0)
+}
+
+fn refresh_time(this_update: i64, next_update: Option<i64>) -> i64 {
+    let refresh_at = match next_update {
+        Some(next_update) => this_update + (next_update - this_update) / 2,
+        None => unix_now() + NO_NEXT_UPDATE_REFRESH,
+    };
+    refresh_at.max(unix_now() + MIN_REFRESH_SECONDS)
+}
+
+fn stapled_until(this_update: i64, next_update: Option<i64>) -> i64 {
+    next_update.unwrap_or(this_update + NO_NEXT_UPDATE_LIFETIME)
+}
+
+// YYYYMMDDHHMMSS[.fff]Z
+fn generalized_time(value: &[u8]) -> Result<i64, String> {
+    let value = String::from_utf8_lossy(value);
+    value
+        .get(..14)
+        .and_then(|seconds| NaiveDateTime::parse_from_str(seconds, "%Y%m%d%H%M%S").ok())
+        .map(|time| time.and_utc().timestamp())
+        .ok_or_else(|| format!("bad time {}", value))
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    digest::digest(&digest::SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn write_file(path: &PathBuf, data: &[u8]) -> std::io::Result<()> {
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path)
+}
+
+fn bad_response() -> String {
+    String::from("malformed OCSP response")
+}
+
+// The little DER we need for OCSP: tag, contents and what follows, lengths up to 4 bytes.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+fn der_expect(input: &[u8], tag: u8) -> Result<(&[u8], &[u8]), String> {
+    match der_element(input) {
+        Some((found, contents, rest)) if found == tag => Ok((contents, rest)),
+        _ => Err(bad_response()),
+    }
+}
+
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let len = contents.len();
+    let mut out = vec![tag];
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|&b| b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend_from_slice(&bytes);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Made with openssl ocsp from a P-256 test CA, see testdata/ocsp/README. All of them
+    // have thisUpdate 2026-10-18 13:51:13 and nextUpdate a week later.
+    const THIS_UPDATE: i64 = 1_792_331_473;
+    const NOW: i64 = THIS_UPDATE + 60 * 60;
+
+    macro_rules! testdata {
+        ($name:expr) => {
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/testdata/ocsp/",
+                $name
+            ))
+        };
+    }
+
+    fn certificate(leaf: &[u8]) -> OcspCertificate {
+        let chain = [
+            rustls::Certificate(leaf.to_vec()),
+            rustls::Certificate(testdata!("issuer.der").to_vec()),
+        ];
+        ocsp_certificate(&chain).unwrap()
+    }
+
+    fn check(response: &[u8], leaf: &[u8], now: i64) -> Result<CertStatus, String> {
+        let certificate = certificate(leaf);
+        check_response(response, &certificate.serial, &certificate.issuer, now)
+    }
+
+    #[test]
+    fn request_for_the_leaf() {
+        let certificate = certificate(testdata!("leaf.der"));
+        assert_eq!(certificate.url, "http://127.0.0.1:18888");
+        assert_eq!(certificate.name, "ocsp.test");
+        assert_eq!(certificate.serial, vec![0x10, 0x01]);
+    }
+
+    #[test]
+    fn good_response() {
+        assert_eq!(
+            check(testdata!("good.der"), testdata!("leaf.der"), NOW),
+            Ok(CertStatus::Good {
+                this_update: THIS_UPDATE,
+                next_update: Some(THIS_UPDATE + 7 * 24 * 60 * 60),
+            })
+        );
+    }
+
+    #[test]
+    fn good_response_from_a_delegated_responder() {
+        assert!(matches!(
+            check(testdata!("delegated.der"), testdata!("leaf.der"), NOW),
+            Ok(CertStatus::Good { .. })
+        ));
+    }
+
+    #[test]
+    fn revoked_response() {
+        assert_eq!(
+            check(testdata!("revoked.der"), testdata!("revoked_leaf.der"), NOW),
+            Ok(CertStatus::Revoked)
+        );
+    }
+
+    #[test]
+    fn unknown_response() {
+        let certificate = certificate(testdata!("leaf.der"));
+        assert_eq!(
+            check_response(
+                testdata!("unknown.der"),
+                &[0x10, 0x03],
+                &certificate.issuer,
+                NOW
+            ),
+            Ok(CertStatus::Unknown)
+        );
+    }
+
+    #[test]
+    fn response_for_another_certificate() {
+        assert!(check(testdata!("good.der"), testdata!("revoked_leaf.der"), NOW).is_err());
+    }
+
+    #[test]
+    fn responses_not_signed_for_the_issuer() {
+        //Signed by another key with the same CA name.
+        assert!(check(testdata!("forged.der"), testdata!("leaf.der"), NOW).is_err());
+        //Signed by a certificate of the issuer without OCSPSigning.
+        assert!(check(testdata!("unauthorized.der"), testdata!("leaf.der"), NOW).is_err());
+        let mut tampered = testdata!("good.der").to_vec();
+        let i = tampered.len() / 3;
+        tampered[i] ^= 1;
+        assert!(check(&tampered, testdata!("leaf.der"), NOW).is_err());
+    }
+
+    #[test]
+    fn responses_out_of_their_time() {
+        let good = testdata!("good.der");
+        let leaf = testdata!("leaf.der");
+        assert!(check(good, leaf, THIS_UPDATE - CLOCK_SKEW + 1).is_ok());
+        assert!(check(good, leaf, THIS_UPDATE - CLOCK_SKEW - 1).is_err());
+        assert!(check(good, leaf, THIS_UPDATE + 7 * 24 * 60 * 60).is_err());
+    }
+
+    #[test]
+    fn malformed_responses() {
+        let good = testdata!("good.der");
+        let leaf = testdata!("leaf.der");
+        for len in &[0, 1, 5, 20, good.len() / 2, good.len() - 1] {
+            assert!(check(&good[..*len], leaf, NOW).is_err(), "{} bytes", len);
+        }
+        //tryLater
+        assert!(check(&[0x30, 0x03, 0x0a, 0x01, 0x03], leaf, NOW).is_err());
+        assert!(check(b"<html>not ocsp</html>", leaf, NOW).is_err());
+    }
+}
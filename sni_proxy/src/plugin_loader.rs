@@ -0,0 +1,239 @@
+use std::{
+    cell::RefCell, collections::HashMap, fmt, io, mem::MaybeUninit, os::raw::c_void, rc::Rc,
+};
+
+use interfaces::{
+    ffi::{call, Bytes, Encoder, FfiCacher, FfiCertificateHandler, FfiOut, FfiSlice, Wire},
+    Cacher, CertificateExpiry, ClientAuthPolicy, LoadSummary, PluginCertificate,
+    PLUGIN_ABI_VERSION, PLUGIN_ABI_VERSION_SYMBOL, PLUGIN_CACHER_SYMBOL,
+    PLUGIN_CERTIFICATE_HANDLER_SYMBOL,
+};
+
+// The cache plugin is loaded once in run() and shared by all connections, the mio loop
+// is single threaded so Rc<RefCell<>> is enough.
+pub type SharedCacher = Rc<RefCell<CacherPlugin>>;
+
+pub struct CacherPlugin {
+    //Declared before _lib so it is dropped while the code it points into is still loaded.
+    cacher: ForeignCacher,
+    _lib: libloading::Library,
+}
+
+impl CacherPlugin {
+    pub fn cacher(&self) -> &dyn Cacher {
+        &self.cacher
+    }
+
+    pub fn cacher_mut(&mut self) -> &mut dyn Cacher {
+        &mut self.cacher
+    }
+}
+
+impl fmt::Debug for CacherPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacherPlugin").finish()
+    }
+}
+
+// The cache plugin behind its FfiCacher, a cache that fails is a cache miss.
+struct ForeignCacher {
+    ffi: FfiCacher,
+}
+
+impl Drop for ForeignCacher {
+    fn drop(&mut self) {
+        (self.ffi.drop)(self.ffi.this);
+    }
+}
+
+impl Cacher for ForeignCacher {
+    fn cache_update_and_test_path(
+        &mut self,
+        host: &str,
+        forward: &str,
+        http_path: &str,
+        data: &Vec<u8>,
+    ) -> io::Result<bool> {
+        call("cache_update_and_test_path", |out| {
+            (self.ffi.update)(
+                self.ffi.this,
+                FfiSlice::new(host.as_bytes()),
+                FfiSlice::new(forward.as_bytes()),
+                FfiSlice::new(http_path.as_bytes()),
+                FfiSlice::new(data),
+                out,
+            )
+        })
+        .map_err(io::Error::other)
+    }
+
+    fn cache_read_path(&self, host: &str, http_path: &str) -> Box<Option<Vec<u8>>> {
+        let cached: Result<Option<Bytes>, String> = call("cache_read_path", |out| {
+            (self.ffi.read)(
+                self.ffi.this,
+                FfiSlice::new(host.as_bytes()),
+                FfiSlice::new(http_path.as_bytes()),
+                out,
+            )
+        });
+        match cached {
+            Ok(cached) => Box::new(cached.map(|bytes| bytes.0)),
+            Err(e) => {
+                error!(target: "0","Cache plugin failed reading {}{}: {}",host,http_path,e);
+                Box::new(None)
+            }
+        }
+    }
+}
+
+// The certificate plugin behind its FfiCertificateHandler, with the methods of
+// interfaces::CertificateHandler. Everything can fail, a panic in the plugin comes back as
+// an Err too.
+pub struct CertificateHandlerPlugin {
+    //Dropped before _lib, Drop calls into the plugin.
+    handler: FfiCertificateHandler,
+    _lib: libloading::Library,
+}
+
+impl Drop for CertificateHandlerPlugin {
+    fn drop(&mut self) {
+        (self.handler.drop)(self.handler.this);
+    }
+}
+
+impl CertificateHandlerPlugin {
+    fn ask<T: Wire>(
+        &self,
+        name: &str,
+        function: extern "C" fn(*mut c_void, FfiOut) -> i32,
+    ) -> Result<T, String> {
+        call(name, |out| function(self.handler.this, out))
+    }
+
+    pub fn get_forwards(&self) -> Result<HashMap<String, String>, String> {
+        self.ask("get_forwards", self.handler.forwards)
+    }
+
+    pub fn certificates(&self) -> Result<Vec<PluginCertificate>, String> {
+        self.ask("certificates", self.handler.certificates)
+    }
+
+    pub fn reload(&mut self) -> Result<(), String> {
+        self.ask("reload", self.handler.reload)
+    }
+
+    pub fn generation(&self) -> Result<u64, String> {
+        self.ask("generation", self.handler.generation)
+    }
+
+    pub fn load_summary(&self) -> Result<LoadSummary, String> {
+        self.ask("load_summary", self.handler.load_summary)
+    }
+
+    pub fn certificate_expiry(&self) -> Result<Vec<CertificateExpiry>, String> {
+        self.ask("certificate_expiry", self.handler.certificate_expiry)
+    }
+
+    pub fn store_certificate(
+        &mut self,
+        names: &[String],
+        forward: &str,
+        fullchain: &str,
+        privkey: &str,
+    ) -> Result<(), String> {
+        let mut encoder = Encoder::default();
+        names.to_vec().encode(&mut encoder);
+        let names = encoder.into_bytes();
+        call("store_certificate", |out| {
+            (self.handler.store_certificate)(
+                self.handler.this,
+                FfiSlice::new(&names),
+                FfiSlice::new(forward.as_bytes()),
+                FfiSlice::new(fullchain.as_bytes()),
+                FfiSlice::new(privkey.as_bytes()),
+                out,
+            )
+        })
+    }
+
+    pub fn client_auth(&self) -> Result<HashMap<String, ClientAuthPolicy>, String> {
+        self.ask("client_auth", self.handler.client_auth)
+    }
+}
+
+// Loads SNI_CACHE_PLUGIN, None if it is not set or can't be loaded and we run without cache.
+pub fn load_cacher() -> Option<SharedCacher> {
+    let path = dotenv::var("SNI_CACHE_PLUGIN").unwrap_or_default();
+    if path.is_empty() {
+        info!(target: "0","No cache plugin configured, running without cache");
+        return None;
+    }
+    let lib = match load_plugin(&path) {
+        Ok(lib) => lib,
+        Err(e) => {
+            error!(target: "0","Unable to load cache plugin, running without cache! {}",e);
+            return None;
+        }
+    };
+    let cacher = match unsafe { create::<FfiCacher>(&lib, PLUGIN_CACHER_SYMBOL) } {
+        Ok(ffi) => ForeignCacher { ffi },
+        Err(e) => {
+            error!(target: "0","Cache plugin {} is not usable, running without cache! {}",path,e);
+            return None;
+        }
+    };
+    info!(target: "0","Loaded cache plugin {}",path);
+    Some(Rc::new(RefCell::new(CacherPlugin { cacher, _lib: lib })))
+}
+
+// Loads SNI_CERT_AND_FORWARDING_PLUGIN, None if it is not set.
+pub fn load_certificate_handler() -> Result<Option<CertificateHandlerPlugin>, String> {
+    let path = dotenv::var("SNI_CERT_AND_FORWARDING_PLUGIN").unwrap_or_default();
+    if path.is_empty() {
+        info!(target: "0","No certificate plugin configured");
+        return Ok(None);
+    }
+    let lib = load_plugin(&path)?;
+    let handler =
+        unsafe { create::<FfiCertificateHandler>(&lib, PLUGIN_CERTIFICATE_HANDLER_SYMBOL) }
+            .map_err(|e| format!("Certificate plugin {} is not usable! {}", path, e))?;
+    info!(target: "0","Loaded certificate plugin {}",path);
+    Ok(Some(CertificateHandlerPlugin { handler, _lib: lib }))
+}
+
+// Opens the library and checks that it was built against the same plugin ABI as we were,
+// before we call anything else in it.
+fn load_plugin(path: &str) -> Result<libloading::Library, String> {
+    let lib = libloading::Library::new(path)
+        .map_err(|e| format!("Unable to open plugin {}: {}", path, e))?;
+    let abi_version = unsafe {
+        let abi_version: libloading::Symbol<extern "C" fn() -> u32> = lib
+            .get(PLUGIN_ABI_VERSION_SYMBOL)
+            .map_err(|_| {
+                format!(
+                    "{} is not a plugin for this proxy (no sni_plugin_abi_version), rebuild it against interfaces ABI {}",
+                    path, PLUGIN_ABI_VERSION
+                )
+            })?;
+        abi_version()
+    };
+    if abi_version != PLUGIN_ABI_VERSION {
+        return Err(format!(
+            "{} has plugin ABI {} but this proxy needs ABI {}",
+            path, abi_version, PLUGIN_ABI_VERSION
+        ));
+    }
+    Ok(lib)
+}
+
+// Calls one of the entry points declared by interfaces::declare_*_plugin!.
+// Only safe after load_plugin has checked the ABI version.
+unsafe fn create<T>(lib: &libloading::Library, symbol: &[u8]) -> Result<T, String> {
+    let name = String::from_utf8_lossy(symbol);
+    let constructor: libloading::Symbol<unsafe extern "C" fn(*mut T, FfiOut) -> i32> = lib
+        .get(symbol)
+        .map_err(|_| format!("missing entry point {}", name))?;
+    let mut created = MaybeUninit::<T>::uninit();
+    call::<(), _>(&name, |error| constructor(created.as_mut_ptr(), error))?;
+    Ok(created.assume_init())
+}
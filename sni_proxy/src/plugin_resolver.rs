@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use interfaces::{choose_certified_key, LoadedCertificate, PluginCertificate, SniNames};
+use rustls::{sign, ClientHello, ResolvesServerCert};
+
+// The certificates of the certificate plugin. The plugin hands them over as PEM and the
+// keys are made here, rustls types don't cross the plugin boundary. update() replaces
+// them as a whole, a handshake never sees half an update.
+pub struct PluginResolver {
+    state: RwLock<Arc<ResolverState>>,
+}
+
+#[derive(Default)]
+struct ResolverState {
+    by_name: SniNames<Vec<LoadedCertificate>>,
+    default: Vec<LoadedCertificate>,
+    //Keyed on the PEMs, so unchanged certificates are not parsed again.
+    by_pem: HashMap<(String, String), LoadedCertificate>,
+}
+
+impl PluginResolver {
+    pub fn new() -> PluginResolver {
+        PluginResolver {
+            state: RwLock::new(Arc::new(ResolverState::default())),
+        }
+    }
+
+    fn state(&self) -> Arc<ResolverState> {
+        match self.state.read() {
+            Ok(state) => state.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    // Certificates that don't parse are logged and left out, the plugin has checked them
+    // already so that is rare.
+    pub fn update(&self, certificates: &[PluginCertificate]) {
+        let old = self.state();
+        let mut new = ResolverState::default();
+        for certificate in certificates {
+            let pem = (certificate.fullchain.clone(), certificate.privkey.clone());
+            let loaded = match old.by_pem.get(&pem).or_else(|| new.by_pem.get(&pem)) {
+                Some(loaded) => loaded.clone(),
+                None => match LoadedCertificate::from_pem(&pem.0, &pem.1) {
+                    Ok(loaded) => loaded,
+                    Err(e) => {
+                        error!(target: "0","Certificate plugin gave us a certificate for {} we can't use: {}",certificate.names.join(", "),e);
+                        continue;
+                    }
+                },
+            };
+            for name in &certificate.names {
+                if !new.by_name.push(name, loaded.clone()) {
+                    warn!(target: "0","Ignoring invalid certificate name {}",name);
+                }
+            }
+            if certificate.default {
+                new.default.push(loaded.clone());
+            }
+            new.by_pem.insert(pem, loaded);
+        }
+        debug!(target: "0","Serving {} certificates from the certificate plugin",new.by_pem.len());
+        match self.state.write() {
+            Ok(mut state) => *state = Arc::new(new),
+            Err(poisoned) => *poisoned.into_inner() = Arc::new(new),
+        }
+    }
+}
+
+impl ResolvesServerCert for PluginResolver {
+    // Clients without SNI or with a name we have no certificate for get the default one.
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+        let state = self.state();
+        let candidates = client_hello
+            .server_name()
+            .and_then(|name| state.by_name.get(name.into()))
+            .unwrap_or(&state.default);
+        choose_certified_key(candidates, client_hello.sigschemes()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Self-signed test certificates and their keys, see interfaces/testdata/README.
+    macro_rules! testdata {
+        ($name:expr) => {
+            include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/../interfaces/testdata/",
+                $name
+            ))
+        };
+    }
+
+    fn certificate(names: &[&str], fullchain: &str, privkey: &str) -> PluginCertificate {
+        PluginCertificate {
+            names: names.iter().map(|name| String::from(*name)).collect(),
+            fullchain: String::from(fullchain),
+            privkey: String::from(privkey),
+            default: false,
+        }
+    }
+
+    #[test]
+    fn unusable_certificates_are_left_out() {
+        let resolver = PluginResolver::new();
+        resolver.update(&[
+            certificate(&["a.test"], testdata!("p256.pem"), testdata!("p256.key")),
+            certificate(&["b.test"], testdata!("p256.pem"), testdata!("rsa.key")),
+        ]);
+        let state = resolver.state();
+        assert!(state.by_name.get("a.test").is_some());
+        assert!(state.by_name.get("b.test").is_none());
+        assert_eq!(state.by_pem.len(), 1);
+    }
+
+    #[test]
+    fn unchanged_certificates_are_not_parsed_again() {
+        let resolver = PluginResolver::new();
+        let a = certificate(&["a.test"], testdata!("p256.pem"), testdata!("p256.key"));
+        let key = |resolver: &PluginResolver| {
+            let state = resolver.state();
+            state.by_name.get("a.test").unwrap()[0].ck.key.clone()
+        };
+        resolver.update(&[a.clone()]);
+        let before = key(&resolver);
+        resolver.update(&[a]);
+        assert!(Arc::ptr_eq(&before, &key(&resolver)));
+    }
+}
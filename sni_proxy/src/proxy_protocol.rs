@@ -0,0 +1,336 @@
+use std::net::SocketAddr;
+
+// HAProxy PROXY protocol, lets a backend see the real client address even though
+// the TCP connection comes from us.
+// https://www.haproxy.org/download/2.2/doc/proxy-protocol.txt
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+//Version 2 and the PROXY command
+const V2_VERSION_COMMAND: u8 = 0x21;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+impl ProxyProtocolVersion {
+    pub fn parse(version: &str) -> Option<ProxyProtocolVersion> {
+        match version.trim().to_lowercase().as_str() {
+            "v1" | "1" => Some(ProxyProtocolVersion::V1),
+            "v2" | "2" => Some(ProxyProtocolVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+// Creates the header that has to be the very first bytes sent to the backend.
+// source is the client, destination is the address the client connected to.
+pub fn proxy_header(
+    version: ProxyProtocolVersion,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::V1 => proxy_header_v1(source, destination),
+        ProxyProtocolVersion::V2 => proxy_header_v2(source, destination),
+    }
+}
+
+fn proxy_header_v1(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let header = match (source, destination) {
+        (SocketAddr::V4(s), SocketAddr::V4(d)) => format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            s.ip(),
+            d.ip(),
+            s.port(),
+            d.port()
+        ),
+        (SocketAddr::V6(s), SocketAddr::V6(d)) => format!(
+            "PROXY TCP6 {} {} {} {}\r\n",
+            s.ip(),
+            d.ip(),
+            s.port(),
+            d.port()
+        ),
+        //v1 can't mix families, and v4 mapped v6 adresses would just confuse the backend.
+        _ => String::from("PROXY UNKNOWN\r\n"),
+    };
+    header.into_bytes()
+}
+
+fn proxy_header_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = Vec::from(&V2_SIGNATURE[..]);
+    header.push(V2_VERSION_COMMAND);
+    match (source, destination) {
+        (SocketAddr::V4(s), SocketAddr::V4(d)) => {
+            header.push(V2_FAMILY_TCP4);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&s.ip().octets());
+            header.extend_from_slice(&d.ip().octets());
+        }
+        _ => {
+            //Mixed families are sent as v6 with v4 mapped adresses.
+            let s = match source {
+                SocketAddr::V4(a) => a.ip().to_ipv6_mapped(),
+                SocketAddr::V6(a) => *a.ip(),
+            };
+            let d = match destination {
+                SocketAddr::V4(a) => a.ip().to_ipv6_mapped(),
+                SocketAddr::V6(a) => *a.ip(),
+            };
+            header.push(V2_FAMILY_TCP6);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&s.octets());
+            header.extend_from_slice(&d.octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProxyHeaderPeek {
+    // We need more bytes before the header is complete.
+    Incomplete,
+    // The stream does not start with a PROXY header.
+    Invalid,
+    // A complete header of header_len bytes. source is None for LOCAL/UNKNOWN
+    // connections, e.g. health checks from the load balancer itself.
+    Header {
+        header_len: usize,
+        source: Option<SocketAddr>,
+    },
+}
+
+//"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n" is the longest v1 header
+const V1_MAX_LEN: usize = 107;
+const V2_HEADER_LEN: usize = 16;
+//v2 can carry TLVs after the addresses, up to the 16 bit length.
+pub const MAX_PROXY_HEADER_LEN: usize = V2_HEADER_LEN + u16::MAX as usize;
+
+// Parses the PROXY header a load balancer in front of us puts before the client data.
+pub fn peek_proxy_header(buf: &[u8]) -> ProxyHeaderPeek {
+    if buf.len() >= V2_SIGNATURE.len() && buf[..V2_SIGNATURE.len()] == V2_SIGNATURE {
+        peek_proxy_header_v2(buf)
+    } else if V2_SIGNATURE.starts_with(buf) || b"PROXY ".starts_with(buf) {
+        ProxyHeaderPeek::Incomplete
+    } else if buf.starts_with(b"PROXY ") {
+        peek_proxy_header_v1(buf)
+    } else {
+        ProxyHeaderPeek::Invalid
+    }
+}
+
+fn peek_proxy_header_v1(buf: &[u8]) -> ProxyHeaderPeek {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        Some(_) => return ProxyHeaderPeek::Invalid,
+        None if buf.len() < V1_MAX_LEN => return ProxyHeaderPeek::Incomplete,
+        None => return ProxyHeaderPeek::Invalid,
+    };
+    let line = String::from_utf8_lossy(&buf[..end]);
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    let source = match parts.as_slice() {
+        ["PROXY", "TCP4", src, _dst, sport, _dport]
+        | ["PROXY", "TCP6", src, _dst, sport, _dport] => match (src.parse(), sport.parse()) {
+            (Ok(ip), Ok(port)) => Some(SocketAddr::new(ip, port)),
+            _ => return ProxyHeaderPeek::Invalid,
+        },
+        ["PROXY", "UNKNOWN", ..] => None,
+        _ => return ProxyHeaderPeek::Invalid,
+    };
+    ProxyHeaderPeek::Header {
+        header_len: end + 2,
+        source,
+    }
+}
+
+fn peek_proxy_header_v2(buf: &[u8]) -> ProxyHeaderPeek {
+    if buf.len() < V2_HEADER_LEN {
+        return ProxyHeaderPeek::Incomplete;
+    }
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = ((buf[14] as usize) << 8) | buf[15] as usize;
+    if version_command >> 4 != 2 {
+        return ProxyHeaderPeek::Invalid;
+    }
+    if buf.len() < V2_HEADER_LEN + len {
+        return ProxyHeaderPeek::Incomplete;
+    }
+    let addresses = &buf[V2_HEADER_LEN..V2_HEADER_LEN + len];
+
+    //Only the PROXY command carries addresses, LOCAL is the balancer talking to us.
+    let source = if version_command != V2_VERSION_COMMAND {
+        None
+    } else if family == V2_FAMILY_TCP4 && addresses.len() >= 12 {
+        let mut ip = [0; 4];
+        ip.copy_from_slice(&addresses[0..4]);
+        let port = ((addresses[8] as u16) << 8) | addresses[9] as u16;
+        Some(SocketAddr::from((ip, port)))
+    } else if family == V2_FAMILY_TCP6 && addresses.len() >= 36 {
+        let mut ip = [0; 16];
+        ip.copy_from_slice(&addresses[0..16]);
+        let port = ((addresses[32] as u16) << 8) | addresses[33] as u16;
+        Some(SocketAddr::from((ip, port)))
+    } else {
+        None
+    };
+    ProxyHeaderPeek::Header {
+        header_len: V2_HEADER_LEN + len,
+        source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> SocketAddr {
+        "192.0.2.1:4000".parse().unwrap()
+    }
+
+    fn listener() -> SocketAddr {
+        "198.51.100.2:443".parse().unwrap()
+    }
+
+    #[test]
+    fn headers_we_send_parse_back() {
+        let v6: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        for version in &[ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for source in &[client(), v6] {
+                let mut header = proxy_header(*version, *source, listener());
+                let header_len = header.len();
+                header.extend_from_slice(b"GET / HTTP/1.1\r\n");
+                //A v6 client of the v4 listener is UNKNOWN in v1, v2 maps the listener
+                let expected = match (version, source) {
+                    (ProxyProtocolVersion::V1, SocketAddr::V6(_)) => None,
+                    _ => Some(*source),
+                };
+                assert_eq!(
+                    peek_proxy_header(&header),
+                    ProxyHeaderPeek::Header {
+                        header_len,
+                        source: expected
+                    },
+                    "{:?} {}",
+                    version,
+                    source
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn v1_unknown() {
+        for header in &[
+            &b"PROXY UNKNOWN\r\n"[..],
+            b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n",
+        ] {
+            assert_eq!(
+                peek_proxy_header(header),
+                ProxyHeaderPeek::Header {
+                    header_len: header.len(),
+                    source: None
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn v2_local_and_unknown_family() {
+        let mut local = Vec::from(&V2_SIGNATURE[..]);
+        //LOCAL command, unspecified family, no addresses
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            peek_proxy_header(&local),
+            ProxyHeaderPeek::Header {
+                header_len: V2_HEADER_LEN,
+                source: None
+            }
+        );
+
+        //PROXY command over a unix socket
+        let mut unix = Vec::from(&V2_SIGNATURE[..]);
+        unix.extend_from_slice(&[V2_VERSION_COMMAND, 0x31, 0x00, 0xd8]);
+        unix.extend_from_slice(&[0; 216]);
+        assert_eq!(
+            peek_proxy_header(&unix),
+            ProxyHeaderPeek::Header {
+                header_len: V2_HEADER_LEN + 216,
+                source: None
+            }
+        );
+    }
+
+    #[test]
+    fn v2_with_tlvs() {
+        let mut header = proxy_header(ProxyProtocolVersion::V2, client(), listener());
+        //A PP2_TYPE_AUTHORITY TLV after the addresses
+        header[15] += 9;
+        header.extend_from_slice(&[0x02, 0x00, 0x06]);
+        header.extend_from_slice(b"a.test");
+        assert_eq!(
+            peek_proxy_header(&header),
+            ProxyHeaderPeek::Header {
+                header_len: header.len(),
+                source: Some(client())
+            }
+        );
+    }
+
+    #[test]
+    fn bad_headers_are_invalid() {
+        let mut signature = proxy_header(ProxyProtocolVersion::V2, client(), listener());
+        signature[11] = 0;
+        let mut version = proxy_header(ProxyProtocolVersion::V2, client(), listener());
+        version[12] = 0x11;
+        let too_long = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LEN));
+        for header in &[
+            &signature[..],
+            &version,
+            b"GET / HTTP/1.1\r\n",
+            b"PROXY TCP4 192.0.2.1\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 99999 443\r\n",
+            b"PROXY TCP5 192.0.2.1 198.51.100.2 4000 443\r\n",
+            too_long.as_bytes(),
+        ] {
+            assert_eq!(
+                peek_proxy_header(header),
+                ProxyHeaderPeek::Invalid,
+                "{:?}",
+                String::from_utf8_lossy(header)
+            );
+        }
+    }
+
+    #[test]
+    fn header_split_over_reads() {
+        for version in &[ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let header = proxy_header(*version, client(), listener());
+            for len in 0..header.len() {
+                assert_eq!(
+                    peek_proxy_header(&header[..len]),
+                    ProxyHeaderPeek::Incomplete,
+                    "{:?} {} bytes",
+                    version,
+                    len
+                );
+            }
+            assert_eq!(
+                peek_proxy_header(&header),
+                ProxyHeaderPeek::Header {
+                    header_len: header.len(),
+                    source: Some(client())
+                }
+            );
+        }
+    }
+}
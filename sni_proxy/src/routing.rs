@@ -0,0 +1,222 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use interfaces::SniNames;
+
+use crate::forward_target::ForwardTarget;
+
+// Decides which forward a Host (or SNI name) goes to. The plugins hand us a flat map and
+// the keys can be:
+//
+//   shop.example.com        Exact host.
+//   *.tenant.example.com    Any single label in front of tenant.example.com, matched
+//                           like a wildcard certificate by SniNames (a.tenant.example.com,
+//                           not a.b.tenant...). "*.com" and the like are ignored.
+//   .example.com            Zone default, example.com and everything below it.
+//
+// A lookup tries them in that order, zones longest first, so the most specific rule
+// always wins no matter the order the plugin returned them in. Nothing matching means
+// DEFAULT_FORWARD, that is up to the caller.
+//
+// Backends given by name are looked up by resolve(), on the ForwardResolver thread before
+// the table is put to use, so a reload or new forwards from the plugin also picks up new
+// addresses.
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    //Exact hosts and wildcards.
+    names: SniNames<String>,
+    //Sorted with the most labels first.
+    zones: Vec<(String, String)>,
+    //The https:// forwards, DEFAULT_FORWARD included.
+    https_targets: Vec<ForwardTarget>,
+    //Keyed on ForwardTarget::address, for the targets that need a lookup.
+    addresses: HashMap<String, SocketAddr>,
+}
+
+impl RoutingTable {
+    pub fn new(forwards: &HashMap<String, String>) -> RoutingTable {
+        let mut table = RoutingTable::default();
+        for (name, forward) in forwards {
+            let name = normalize_host(name);
+            if let Some(zone) = name.strip_prefix('.') {
+                table.zones.push((String::from(zone), forward.clone()));
+            } else if !name.is_empty() && !table.names.insert(&name, forward.clone()) {
+                warn!(target: "0","Ignoring forward for {}, not a valid wildcard",name);
+            }
+        }
+        table.zones.sort_by(|(a, _), (b, _)| {
+            b.matches('.')
+                .count()
+                .cmp(&a.matches('.').count())
+                .then_with(|| a.cmp(b))
+        });
+        let default_forward = dotenv::var("DEFAULT_FORWARD").ok();
+        for forward in forwards.values().chain(default_forward.as_ref()) {
+            let target = ForwardTarget::parse(forward);
+            if target.is_https() && !table.https_targets.contains(&target) {
+                table.https_targets.push(target);
+            }
+        }
+        table
+    }
+
+    // Looks up the backends given by name. Blocks, so it is only called on the
+    // ForwardResolver thread, or at startup before the event loop runs.
+    pub fn resolve(&mut self) {
+        for target in &self.https_targets {
+            if !target.needs_lookup() || self.addresses.contains_key(&target.address) {
+                continue;
+            }
+            match target.resolve() {
+                Ok(address) => {
+                    debug!(target: "0","Forward {} is {}",target.address,address);
+                    self.addresses.insert(target.address.clone(), address);
+                }
+                Err(e) => {
+                    error!(target: "0","Forward {} will answer 502: {}",target.address,e);
+                }
+            }
+        }
+    }
+
+    pub fn https_targets(&self) -> &[ForwardTarget] {
+        &self.https_targets
+    }
+
+    // Where to connect for a target from this table.
+    pub fn socket_addr(&self, target: &ForwardTarget) -> Result<SocketAddr, String> {
+        if let Some(address) = target.socket_addr() {
+            return Ok(address);
+        }
+        if !target.needs_lookup() {
+            return Err(format!("{} is not an IP address and port", target.address));
+        }
+        self.addresses.get(&target.address).copied().ok_or_else(|| {
+            format!(
+                "{} did not resolve when the forwards were loaded",
+                target.address
+            )
+        })
+    }
+
+    pub fn lookup(&self, host: &str) -> Option<&String> {
+        let host = normalize_host(host);
+        if let Some(forward) = self.names.get(&host) {
+            return Some(forward);
+        }
+        self.zones
+            .iter()
+            .find(|(zone, _)| {
+                host == *zone
+                    || (host.ends_with(zone.as_str())
+                        && host[..host.len() - zone.len()].ends_with('.'))
+            })
+            .map(|(_, forward)| forward)
+    }
+
+    // The exact hosts and their forwards, wildcards and zones are not hosts.
+    pub fn hosts(&self) -> impl Iterator<Item = (String, &String)> {
+        self.names
+            .iter()
+            .filter(|(name, _)| !name.starts_with("*."))
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.iter().count() + self.zones.len()
+    }
+}
+
+// Host headers can carry a port ("example.com:8443", "[::1]:443") and names can be
+// written with a trailing dot or in any case, none of which matters for routing.
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = if host.starts_with('[') {
+        match host.find(']') {
+            Some(i) => &host[..=i],
+            None => host,
+        }
+    } else {
+        match host.rfind(':') {
+            //More than one colon is a bare IPv6 address, not a port.
+            Some(i) if host[..i].find(':').is_none() => &host[..i],
+            _ => host,
+        }
+    };
+    host.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(rules: &[(&str, &str)]) -> RoutingTable {
+        let forwards: HashMap<String, String> = rules
+            .iter()
+            .map(|(name, forward)| (name.to_string(), forward.to_string()))
+            .collect();
+        RoutingTable::new(&forwards)
+    }
+
+    fn route<'a>(table: &'a RoutingTable, host: &str) -> Option<&'a str> {
+        table.lookup(host).map(String::as_str)
+    }
+
+    #[test]
+    fn most_specific_rule_wins() {
+        let table = table(&[
+            (".example.com", "127.0.0.1:1"),
+            (".tenant.example.com", "127.0.0.1:2"),
+            ("*.tenant.example.com", "127.0.0.1:3"),
+            ("shop.tenant.example.com", "127.0.0.1:4"),
+        ]);
+        assert_eq!(
+            route(&table, "shop.tenant.example.com"),
+            Some("127.0.0.1:4")
+        );
+        assert_eq!(
+            route(&table, "blog.tenant.example.com"),
+            Some("127.0.0.1:3")
+        );
+        //A wildcard is one label, deeper names fall to the zones
+        assert_eq!(route(&table, "a.b.tenant.example.com"), Some("127.0.0.1:2"));
+        assert_eq!(route(&table, "tenant.example.com"), Some("127.0.0.1:2"));
+        assert_eq!(route(&table, "www.example.com"), Some("127.0.0.1:1"));
+        assert_eq!(route(&table, "example.com"), Some("127.0.0.1:1"));
+        assert_eq!(route(&table, "badexample.com"), None);
+        assert_eq!(route(&table, "example.org"), None);
+        assert_eq!(table.len(), 4);
+        let hosts: Vec<String> = table.hosts().map(|(host, _)| host).collect();
+        assert_eq!(hosts, vec!["shop.tenant.example.com"]);
+    }
+
+    #[test]
+    fn hosts_are_normalized() {
+        let table = table(&[
+            ("Shop.Example.COM.", "127.0.0.1:1"),
+            ("*.Example.com", "127.0.0.1:2"),
+        ]);
+        assert_eq!(route(&table, "shop.example.com:8443"), Some("127.0.0.1:1"));
+        assert_eq!(route(&table, "SHOP.example.com."), Some("127.0.0.1:1"));
+        assert_eq!(route(&table, "www.EXAMPLE.com"), Some("127.0.0.1:2"));
+        assert_eq!(route(&table, "example.com"), None);
+    }
+
+    #[test]
+    fn invalid_wildcards_are_ignored() {
+        let table = table(&[
+            ("*.com", "127.0.0.1:1"),
+            ("w*.example.com", "127.0.0.1:2"),
+            ("*", "127.0.0.1:3"),
+        ]);
+        assert_eq!(route(&table, "example.com"), None);
+        assert_eq!(route(&table, "www.example.com"), None);
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn normalize_hosts() {
+        assert_eq!(normalize_host("Example.com:8443"), "example.com");
+        assert_eq!(normalize_host("example.com."), "example.com");
+        assert_eq!(normalize_host("[::1]:443"), "[::1]");
+        assert_eq!(normalize_host("::1"), "::1");
+    }
+}
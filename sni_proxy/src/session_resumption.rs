@@ -0,0 +1,269 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use ring::{
+    aead, digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use rustls::{NoServerSessionStorage, ProducesTickets, ServerConfig, ServerSessionMemoryCache};
+
+// Sessions kept for resumption by session id, TLS_SESSION_CACHE overrides it.
+const DEFAULT_SESSION_CACHE: &str = "4096";
+// A new ticket key this often, the one before it still decrypts for as long again.
+const DEFAULT_ROTATE_HOURS: &str = "12";
+// How often TLS_TICKET_KEY_FILE is looked at for new keys.
+const KEY_FILE_CHECK: Duration = Duration::from_secs(60);
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+// A session cache and a ticketer on the shared ServerConfig, so clients can resume their
+// sessions. With TLS_TICKET_KEY_FILE every proxy reading the same file takes the others'
+// tickets.
+pub fn configure_resumption(config: &mut ServerConfig) -> Result<(), String> {
+    let cache_size: usize = dotenv::var("TLS_SESSION_CACHE")
+        .unwrap_or_else(|_| String::from(DEFAULT_SESSION_CACHE))
+        .trim()
+        .parse()
+        .map_err(|_| String::from("TLS_SESSION_CACHE is not a number"))?;
+    config.session_storage = if cache_size > 0 {
+        ServerSessionMemoryCache::new(cache_size)
+    } else {
+        Arc::new(NoServerSessionStorage {})
+    };
+
+    let tickets: bool = dotenv::var("TLS_TICKETS")
+        .unwrap_or(String::from("true"))
+        .parse()
+        .unwrap_or(true);
+    let rotate_hours: u64 = dotenv::var("TLS_TICKET_ROTATE_HOURS")
+        .unwrap_or_else(|_| String::from(DEFAULT_ROTATE_HOURS))
+        .trim()
+        .parse()
+        .ok()
+        .filter(|hours| *hours > 0)
+        .ok_or_else(|| String::from("TLS_TICKET_ROTATE_HOURS is not a number of hours"))?;
+    let key_file = dotenv::var("TLS_TICKET_KEY_FILE").ok().map(PathBuf::from);
+    config.ticketer = if tickets {
+        Arc::new(RotatingTicketer::new(
+            key_file.clone(),
+            Duration::from_secs(rotate_hours * 60 * 60),
+        )?)
+    } else {
+        Arc::new(NoTickets)
+    };
+
+    info!(target: "0","TLS session cache {} sessions, tickets {}",cache_size,match (tickets, key_file) {
+        (false, _) => String::from("off"),
+        (true, Some(key_file)) => format!("with keys from {}", key_file.display()),
+        (true, None) => format!("with a new key every {} hours", rotate_hours),
+    });
+    Ok(())
+}
+
+// For configs that must not resume sessions.
+pub struct NoTickets;
+
+impl ProducesTickets for NoTickets {
+    fn enabled(&self) -> bool {
+        false
+    }
+    fn get_lifetime(&self) -> u32 {
+        0
+    }
+    fn encrypt(&self, _plain: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+    fn decrypt(&self, _cipher: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+struct TicketKey {
+    //So a ticket says which key it needs.
+    id: [u8; KEY_ID_LEN],
+    key: aead::LessSafeKey,
+}
+
+struct TicketKeys {
+    //The first encrypts, all of them decrypt.
+    keys: Vec<TicketKey>,
+    //When we make a new key, or look at the key file again.
+    next_check: Instant,
+    file_modified: Option<SystemTime>,
+}
+
+// Tickets are id || nonce || ChaCha20-Poly1305 sealed session. Without a key file we make
+// a new key every rotate interval and keep the one before it. With one, whoever writes
+// the file rotates the keys, one base64 encoded 32 byte key per line, newest first.
+pub struct RotatingTicketer {
+    key_file: Option<PathBuf>,
+    rotate: Duration,
+    rng: SystemRandom,
+    keys: Mutex<TicketKeys>,
+}
+
+impl RotatingTicketer {
+    pub fn new(key_file: Option<PathBuf>, rotate: Duration) -> Result<RotatingTicketer, String> {
+        let rng = SystemRandom::new();
+        let (keys, file_modified) = match key_file.as_ref() {
+            Some(key_file) => {
+                let (keys, modified) = read_key_file(key_file)?;
+                (keys, Some(modified))
+            }
+            None => (vec![new_key(&rng)?], None),
+        };
+        Ok(RotatingTicketer {
+            keys: Mutex::new(TicketKeys {
+                keys,
+                next_check: Instant::now() + check_interval(&key_file, rotate),
+                file_modified,
+            }),
+            key_file,
+            rotate,
+            rng,
+        })
+    }
+
+    fn maybe_rotate(&self, keys: &mut TicketKeys) {
+        if Instant::now() < keys.next_check {
+            return;
+        }
+        keys.next_check = Instant::now() + check_interval(&self.key_file, self.rotate);
+        match self.key_file.as_ref() {
+            Some(key_file) => {
+                let modified = fs::metadata(key_file).and_then(|m| m.modified()).ok();
+                if modified.is_none() || modified == keys.file_modified {
+                    return;
+                }
+                match read_key_file(key_file) {
+                    Ok((new_keys, modified)) => {
+                        info!(target: "0","Loaded {} TLS ticket keys from {}",new_keys.len(),key_file.display());
+                        keys.keys = new_keys;
+                        keys.file_modified = Some(modified);
+                    }
+                    Err(e) => {
+                        warn!(target: "0","Keeping the TLS ticket keys we have: {}",e);
+                    }
+                }
+            }
+            None => match new_key(&self.rng) {
+                Ok(key) => {
+                    debug!(target: "0","New TLS ticket key");
+                    keys.keys.insert(0, key);
+                    keys.keys.truncate(2);
+                }
+                Err(e) => {
+                    warn!(target: "0","Keeping the TLS ticket key we have: {}",e);
+                }
+            },
+        }
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.rotate.as_secs() as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut keys = self.keys.lock().ok()?;
+        self.maybe_rotate(&mut keys);
+        let key = keys.keys.first()?;
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+        let mut sealed = plain.to_vec();
+        key.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut sealed,
+            )
+            .ok()?;
+        let mut ticket = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + sealed.len());
+        ticket.extend_from_slice(&key.id);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < KEY_ID_LEN + NONCE_LEN {
+            return None;
+        }
+        let (id, rest) = cipher.split_at(KEY_ID_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let mut keys = self.keys.lock().ok()?;
+        self.maybe_rotate(&mut keys);
+        let key = keys.keys.iter().find(|key| key.id == id)?;
+        let mut plain = sealed.to_vec();
+        let len = key
+            .key
+            .open_in_place(
+                aead::Nonce::try_assume_unique_for_key(nonce).ok()?,
+                aead::Aad::empty(),
+                &mut plain,
+            )
+            .ok()?
+            .len();
+        plain.truncate(len);
+        Some(plain)
+    }
+}
+
+fn check_interval(key_file: &Option<PathBuf>, rotate: Duration) -> Duration {
+    if key_file.is_some() {
+        KEY_FILE_CHECK
+    } else {
+        rotate
+    }
+}
+
+fn new_key(rng: &SystemRandom) -> Result<TicketKey, String> {
+    let mut key = [0u8; KEY_LEN];
+    rng.fill(&mut key)
+        .map_err(|_| String::from("unable to make a TLS ticket key"))?;
+    ticket_key(&key)
+}
+
+fn ticket_key(key: &[u8]) -> Result<TicketKey, String> {
+    let mut id = [0u8; KEY_ID_LEN];
+    id.copy_from_slice(&digest::digest(&digest::SHA256, key).as_ref()[..KEY_ID_LEN]);
+    let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key)
+        .map_err(|_| format!("TLS ticket keys must be {} bytes", KEY_LEN))?;
+    Ok(TicketKey {
+        id,
+        key: aead::LessSafeKey::new(key),
+    })
+}
+
+fn read_key_file(key_file: &PathBuf) -> Result<(Vec<TicketKey>, SystemTime), String> {
+    let error = |e: std::io::Error| format!("{}: {}", key_file.display(), e);
+    let modified = fs::metadata(key_file)
+        .and_then(|m| m.modified())
+        .map_err(error)?;
+    let keys = fs::read_to_string(key_file)
+        .map_err(error)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            base64::decode(line)
+                .map_err(|_| String::from("a TLS ticket key is not base64"))
+                .and_then(|key| ticket_key(&key))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", key_file.display(), e))?;
+    if keys.is_empty() {
+        return Err(format!("{}: no TLS ticket keys", key_file.display()));
+    }
+    Ok((keys, modified))
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+
+use rustls::{ProtocolVersion, ServerConfig, SupportedCipherSuite, ALL_CIPHERSUITES};
+
+// Mozilla's server side TLS recommendations, TLS_POLICY picks one and the other TLS_
+// variables change parts of it.
+const DEFAULT_POLICY: &str = "intermediate";
+// What we speak to clients, advertised when TLS_ALPN isn't set.
+const DEFAULT_ALPN: &str = "http/1.1";
+const KNOWN_ALPN: &[&str] = &["http/1.1", "http/1.0"];
+// rustls 0.18 offers these in this order for every config and has no setting for them.
+// TLS_KX_GROUPS can only name all of them, a restriction would not be applied.
+const KX_GROUPS: &[&str] = &["X25519", "secp384r1", "secp256r1"];
+// Oldest first.
+const VERSIONS: &[(&str, ProtocolVersion)] = &[
+    ("1.2", ProtocolVersion::TLSv1_2),
+    ("1.3", ProtocolVersion::TLSv1_3),
+];
+
+// The TLS versions, cipher suites and ALPN protocols every HTTPS connection is offered,
+// including the per host configs for client certificates that are cloned from it.
+pub struct TlsPolicy {
+    name: String,
+    versions: Vec<ProtocolVersion>,
+    ciphersuites: Vec<&'static SupportedCipherSuite>,
+    alpn_protocols: Vec<String>,
+}
+
+impl TlsPolicy {
+    pub fn from_env() -> Result<TlsPolicy, String> {
+        let name = dotenv::var("TLS_POLICY")
+            .unwrap_or_else(|_| String::from(DEFAULT_POLICY))
+            .trim()
+            .to_lowercase();
+        //Indexes into VERSIONS.
+        let (mut min, mut max) = match name.as_str() {
+            "modern" => (1, 1),
+            "intermediate" => (0, 1),
+            _ => {
+                return Err(format!(
+                    "unknown TLS_POLICY {:?}, modern or intermediate",
+                    name
+                ))
+            }
+        };
+        if let Ok(version) = dotenv::var("TLS_MIN_VERSION") {
+            min = version_index("TLS_MIN_VERSION", &version)?;
+        }
+        if let Ok(version) = dotenv::var("TLS_MAX_VERSION") {
+            max = version_index("TLS_MAX_VERSION", &version)?;
+        }
+        if min > max {
+            return Err(String::from(
+                "TLS_MIN_VERSION is newer than TLS_MAX_VERSION",
+            ));
+        }
+        let versions: Vec<ProtocolVersion> = VERSIONS[min..=max].iter().map(|(_, v)| *v).collect();
+
+        let ciphersuites: Vec<&'static SupportedCipherSuite> = match dotenv::var("TLS_CIPHERSUITES")
+        {
+            Ok(names) => split_list(&names)
+                .map(|name| {
+                    ALL_CIPHERSUITES
+                        .iter()
+                        .copied()
+                        .find(|suite| suite_name(suite).eq_ignore_ascii_case(name))
+                        .ok_or_else(|| format!("unknown cipher suite {} in TLS_CIPHERSUITES", name))
+                })
+                .collect::<Result<_, _>>()?,
+            //Both presets take all of them for the versions they have, they are all AEAD
+            //with forward secrecy.
+            Err(_) => ALL_CIPHERSUITES
+                .iter()
+                .copied()
+                .filter(|suite| versions.iter().any(|v| suite.usable_for_version(*v)))
+                .collect(),
+        };
+        for version in &versions {
+            if !ciphersuites
+                .iter()
+                .any(|suite| suite.usable_for_version(*version))
+            {
+                return Err(format!(
+                    "no cipher suite in TLS_CIPHERSUITES for {:?}",
+                    version
+                ));
+            }
+        }
+
+        if let Ok(names) = dotenv::var("TLS_KX_GROUPS") {
+            check_kx_groups(&names)?;
+        }
+
+        let alpn_protocols: Vec<String> =
+            split_list(&dotenv::var("TLS_ALPN").unwrap_or_else(|_| String::from(DEFAULT_ALPN)))
+                .map(String::from)
+                .collect();
+        if let Some(unknown) = alpn_protocols
+            .iter()
+            .find(|protocol| !KNOWN_ALPN.contains(&protocol.as_str()))
+        {
+            return Err(format!(
+                "we don't speak {} from TLS_ALPN, only {}",
+                unknown,
+                KNOWN_ALPN.join(";")
+            ));
+        }
+
+        Ok(TlsPolicy {
+            name,
+            versions,
+            ciphersuites,
+            alpn_protocols,
+        })
+    }
+
+    pub fn apply(&self, config: &mut ServerConfig) {
+        config.versions = self.versions.clone();
+        config.ciphersuites = self.ciphersuites.clone();
+        config.alpn_protocols = self
+            .alpn_protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+    }
+}
+
+impl fmt::Display for TlsPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let versions: Vec<String> = self.versions.iter().map(|v| format!("{:?}", v)).collect();
+        let ciphersuites: Vec<String> = self.ciphersuites.iter().map(|s| suite_name(s)).collect();
+        write!(
+            f,
+            "{}: versions {}, cipher suites {}, key exchange groups {}, ALPN {}",
+            self.name,
+            versions.join(" "),
+            ciphersuites.join(" "),
+            KX_GROUPS.join(" "),
+            if self.alpn_protocols.is_empty() {
+                String::from("off")
+            } else {
+                self.alpn_protocols.join(" ")
+            }
+        )
+    }
+}
+
+// Fails for anything but the groups rustls offers anyway, so asking for fewer groups stops
+// the start instead of being ignored.
+fn check_kx_groups(names: &str) -> Result<(), String> {
+    let mut groups: Vec<&str> = Vec::new();
+    for name in split_list(names) {
+        match KX_GROUPS
+            .iter()
+            .find(|group| group.eq_ignore_ascii_case(name))
+        {
+            Some(group) if !groups.contains(group) => groups.push(group),
+            Some(_) => {}
+            None => {
+                return Err(format!(
+                    "unknown key exchange group {} in TLS_KX_GROUPS",
+                    name
+                ))
+            }
+        }
+    }
+    if groups.len() != KX_GROUPS.len() {
+        return Err(format!(
+            "TLS_KX_GROUPS can't leave out groups, rustls 0.18 always offers {}",
+            KX_GROUPS.join(" ")
+        ));
+    }
+    Ok(())
+}
+
+fn version_index(variable: &str, version: &str) -> Result<usize, String> {
+    let version = version.trim().to_lowercase();
+    let version = version.trim_start_matches("tlsv").trim_start_matches("tls");
+    VERSIONS
+        .iter()
+        .position(|(name, _)| *name == version)
+        .ok_or_else(|| format!("unknown {} {:?}, 1.2 or 1.3", variable, version))
+}
+
+//The name rustls and the IANA registry have, e.g. TLS13_AES_128_GCM_SHA256.
+fn suite_name(suite: &SupportedCipherSuite) -> String {
+    format!("{:?}", suite.suite)
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(';')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kx_groups_can_not_be_restricted() {
+        assert!(check_kx_groups("X25519;secp384r1;secp256r1").is_ok());
+        assert!(check_kx_groups("secp256r1; x25519; SECP384R1").is_ok());
+        assert!(check_kx_groups("X25519").is_err());
+        assert!(check_kx_groups("X25519;X25519;secp384r1").is_err());
+        assert!(check_kx_groups("X25519;secp384r1;secp256r1;ffdhe2048").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::{collections::HashMap, fmt, fs::File, io::BufReader, sync::Arc};
+
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    ClientConfig, ClientSession, PrivateKey,
+};
+
+use crate::{forward_target::ForwardTarget, http_client::DEFAULT_CA_FILE};
+
+// (CA file, client certificate and key files)
+type ConfigKey = (String, Option<(String, String)>);
+
+// ClientConfigs for the https:// forwards of a routing table. Reading the CA and client
+// certificate files blocks, so they are made with the table on the ForwardResolver thread
+// and the event loop only looks them up. Backends with the same CA and client certificate
+// share one, and with it the TLS session cache.
+#[derive(Default)]
+pub struct UpstreamTlsConfigs {
+    //A file that could not be read is kept as the error, for the 502 it leads to.
+    configs: HashMap<ConfigKey, Result<Arc<ClientConfig>, String>>,
+}
+
+impl fmt::Debug for UpstreamTlsConfigs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpstreamTlsConfigs").finish()
+    }
+}
+
+impl UpstreamTlsConfigs {
+    // Reads the files for targets. Configs previous already has for the same files are
+    // kept, a reload passes an empty one so everything is read again.
+    pub fn load(targets: &[ForwardTarget], previous: &UpstreamTlsConfigs) -> UpstreamTlsConfigs {
+        let mut configs = HashMap::new();
+        for target in targets {
+            //session() gives the error for these.
+            let key = match config_key(target) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            if configs.contains_key(&key) {
+                continue;
+            }
+            let config = match previous.configs.get(&key) {
+                Some(Ok(config)) => Ok(config.clone()),
+                _ => client_config(&key).map(Arc::new),
+            };
+            if let Err(e) = &config {
+                error!(target: "0","Forward {} will answer 502: {}",target.address,e);
+            }
+            configs.insert(key, config);
+        }
+        UpstreamTlsConfigs { configs }
+    }
+
+    pub fn session(&self, target: &ForwardTarget) -> Result<ClientSession, String> {
+        let server_name = target
+            .server_name()
+            .ok_or_else(|| String::from("an IP address needs sni= for the backend certificate"))?;
+        let name = webpki::DNSNameRef::try_from_ascii_str(server_name)
+            .map_err(|_| format!("{} is not a DNS name", server_name))?;
+        let config = self
+            .configs
+            .get(&config_key(target)?)
+            .ok_or_else(|| format!("no TLS config was loaded for {}", target.address))?
+            .clone()?;
+        Ok(ClientSession::new(&config, name))
+    }
+}
+
+fn config_key(target: &ForwardTarget) -> Result<ConfigKey, String> {
+    let client_cert = match (target.client_cert.as_ref(), target.client_key.as_ref()) {
+        (Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+        (None, None) => None,
+        _ => return Err(String::from("cert= and key= go together")),
+    };
+    Ok((
+        target.ca_file.clone().unwrap_or_else(|| {
+            dotenv::var("UPSTREAM_CA_FILE").unwrap_or_else(|_| String::from(DEFAULT_CA_FILE))
+        }),
+        client_cert,
+    ))
+}
+
+fn client_config((ca_file, client_cert): &ConfigKey) -> Result<ClientConfig, String> {
+    let mut config = ClientConfig::new();
+    let (added, _) = config
+        .root_store
+        .add_pem_file(&mut BufReader::new(open(ca_file)?))
+        .map_err(|_| format!("Unable to read CA certificates from {}", ca_file))?;
+    if added == 0 {
+        return Err(format!("No CA certificates in {}", ca_file));
+    }
+    if let Some((cert_file, key_file)) = client_cert {
+        let chain = certs(&mut BufReader::new(open(cert_file)?))
+            .map_err(|_| format!("Unable to read certificates from {}", cert_file))?;
+        if chain.is_empty() {
+            return Err(format!("No certificate in {}", cert_file));
+        }
+        config
+            .set_single_client_cert(chain, private_key(key_file)?)
+            .map_err(|e| format!("Bad client certificate {}: {:?}", cert_file, e))?;
+    }
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    Ok(config)
+}
+
+fn private_key(key_file: &str) -> Result<PrivateKey, String> {
+    let error = || format!("Unable to read the private key in {}", key_file);
+    let pkcs8 = pkcs8_private_keys(&mut BufReader::new(open(key_file)?)).map_err(|_| error())?;
+    let rsa = rsa_private_keys(&mut BufReader::new(open(key_file)?)).map_err(|_| error())?;
+    pkcs8.into_iter().chain(rsa).next().ok_or_else(error)
+}
+
+fn open(file: &str) -> Result<File, String> {
+    File::open(file).map_err(|e| format!("Unable to open {}: {}", file, e))
+}